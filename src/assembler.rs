@@ -0,0 +1,739 @@
+use crate::memory::{EXTENDED_MEMORY_SIZE, PROGRAM_START};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Assembles the Cowgod-style mnemonics printed by the disassembler.
+//
+//   ; comments run to the end of the line
+//   ROWS = 5                   constants, usable anywhere a number is
+//   start:                     labels mark the address of the next statement
+//       LD I, sprite
+//       DRW V0, V1, ROWS
+//       JP start
+//   sprite:
+//       SPRITE "#..#...."      one byte per 8 pixels, '#' or '1' is set
+//       DB 0x3C, 0b00011000    raw bytes, DW for 16-bit words
+//       INCLUDE "tiles.bin"    binary file, relative to the source
+//
+//   MACRO swap a, b            macros substitute their parameters
+//       LD VF, a
+//       LD a, b
+//       LD b, VF
+//   ENDM
+//       swap V1, V2
+
+// How deeply macros may invoke other macros
+const MACRO_DEPTH: usize = 16;
+
+// An assembly failure, pointing at the line and column (both from 1) that caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    pub(crate) fn new(line: usize, column: usize, message: String) -> Self {
+        Self { line, column, message }
+    }
+
+    fn at(token: &Token, message: String) -> Self {
+        Self::new(token.line, token.column, message)
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+// Assemble source into a ROM loaded at PROGRAM_START. Included files are
+// looked up relative to `include_dir`.
+pub fn assemble(source: &str, include_dir: &Path) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    for (number, text) in source.lines().enumerate() {
+        let tokens = tokenize(text, number + 1)?;
+        if !tokens.is_empty() {
+            lines.push(tokens);
+        }
+    }
+    let lines = expand_macros(lines)?;
+
+    // First pass: lay out every statement and record labels and constants
+    let mut symbols = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = PROGRAM_START;
+    for line in lines {
+        let mut tokens = &line[..];
+        if let [Token { kind: Kind::Ident(name), .. }, Token { kind: Kind::Colon, .. }, rest @ ..] = tokens {
+            define(&mut symbols, &tokens[0], name, address as i64)?;
+            tokens = rest;
+        }
+        if let [Token { kind: Kind::Ident(name), .. }, Token { kind: Kind::Equals, .. }, rest @ ..] = tokens {
+            let value = Expr::parse(&tokens[1], rest)?.eval(&symbols)?;
+            define(&mut symbols, &tokens[0], name, value)?;
+            continue;
+        }
+        if tokens.is_empty() {
+            continue;
+        }
+        let statement = Statement::parse(tokens, include_dir)?;
+        address += statement.size();
+        if address > EXTENDED_MEMORY_SIZE {
+            let max = EXTENDED_MEMORY_SIZE - PROGRAM_START;
+            return Err(AsmError::at(&tokens[0], format!("program is larger than the {} bytes that fit in memory", max)));
+        }
+        statements.push(statement);
+    }
+
+    // Second pass: encode with every symbol known
+    let mut rom = Vec::new();
+    for statement in statements {
+        statement.encode(&symbols, &mut rom)?;
+    }
+    Ok(rom)
+}
+
+fn define(symbols: &mut HashMap<String, i64>, token: &Token, name: &str, value: i64) -> Result<(), AsmError> {
+    if symbols.insert(name.to_string(), value).is_some() {
+        return Err(AsmError::at(token, format!("'{}' is already defined", name)));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Kind {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Comma,
+    Colon,
+    Equals,
+    Plus,
+    Minus,
+    LeftBracket,
+    RightBracket,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: Kind,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn ident(&self) -> Option<&str> {
+        match &self.kind {
+            Kind::Ident(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    // Identifiers compare case-insensitively when used as keywords
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.ident().is_some_and(|name| name.eq_ignore_ascii_case(keyword))
+    }
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AsmError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        let kind = match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ',' => Kind::Comma,
+            ':' => Kind::Colon,
+            '=' => Kind::Equals,
+            '+' => Kind::Plus,
+            '-' => Kind::Minus,
+            '[' => Kind::LeftBracket,
+            ']' => Kind::RightBracket,
+            '"' => {
+                let end = chars[i + 1..].iter().position(|&c| c == '"')
+                    .ok_or_else(|| AsmError::new(line, column, "unterminated string".to_string()))?;
+                let value = chars[i + 1..i + 1 + end].iter().collect();
+                i += end + 2;
+                tokens.push(Token { kind: Kind::Str(value), line, column });
+                continue;
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let end = chars[i..].iter()
+                    .position(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                    .map_or(chars.len(), |end| i + end);
+                let word: String = chars[i..end].iter().collect();
+                i = end;
+                let kind = if c.is_ascii_digit() {
+                    Kind::Number(parse_number(&word)
+                        .ok_or_else(|| AsmError::new(line, column, format!("invalid number '{}'", word)))?)
+                } else {
+                    Kind::Ident(word)
+                };
+                tokens.push(Token { kind, line, column });
+                continue;
+            }
+            c => return Err(AsmError::new(line, column, format!("unexpected character '{}'", c))),
+        };
+        tokens.push(Token { kind, line, column });
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+// Numbers are decimal, 0x hexadecimal or 0b binary
+fn parse_number(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Split tokens on commas; an empty list gives no groups
+fn split_commas(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    tokens.split(|token| token.kind == Kind::Comma).collect()
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Vec<Token>>,
+}
+
+// Collect macro definitions and replace every invocation with its body
+fn expand_macros(lines: Vec<Vec<Token>>) -> Result<Vec<Vec<Token>>, AsmError> {
+    let mut macros = HashMap::new();
+    let mut out = Vec::new();
+    let mut lines = lines.into_iter();
+    while let Some(line) = lines.next() {
+        if !line[0].is_keyword("MACRO") {
+            expand_line(line, &macros, 0, &mut out)?;
+            continue;
+        }
+        let name = match line.get(1).and_then(Token::ident) {
+            Some(name) => name.to_string(),
+            None => return Err(AsmError::at(&line[0], "expected a macro name".to_string())),
+        };
+        let mut params = Vec::new();
+        for group in split_commas(&line[2..]) {
+            match group {
+                [token] if token.ident().is_some() => params.push(token.ident().unwrap_or_default().to_string()),
+                _ => {
+                    let token = group.first().unwrap_or(&line[1]);
+                    return Err(AsmError::at(token, "expected a parameter name".to_string()));
+                }
+            }
+        }
+        let mut body = Vec::new();
+        loop {
+            match lines.next() {
+                Some(body_line) if body_line[0].is_keyword("ENDM") => break,
+                Some(body_line) => body.push(body_line),
+                None => return Err(AsmError::at(&line[0], format!("macro '{}' is missing ENDM", name))),
+            }
+        }
+        if macros.insert(name.clone(), Macro { params, body }).is_some() {
+            return Err(AsmError::at(&line[1], format!("macro '{}' is already defined", name)));
+        }
+    }
+    Ok(out)
+}
+
+fn expand_line(line: Vec<Token>, macros: &HashMap<String, Macro>, depth: usize, out: &mut Vec<Vec<Token>>) -> Result<(), AsmError> {
+    // A label may precede the invocation
+    let start = if matches!(line.get(1), Some(Token { kind: Kind::Colon, .. })) { 2 } else { 0 };
+    let definition = line.get(start).and_then(Token::ident).and_then(|name| macros.get(name));
+    let definition = match definition {
+        Some(definition) => definition,
+        None => {
+            out.push(line);
+            return Ok(());
+        }
+    };
+    let invocation = &line[start];
+    if depth == MACRO_DEPTH {
+        return Err(AsmError::at(invocation, "macros nested too deeply".to_string()));
+    }
+    let args = split_commas(&line[start + 1..]);
+    if args.len() != definition.params.len() || args.iter().any(|arg| arg.is_empty()) {
+        return Err(AsmError::at(invocation, format!(
+            "macro '{}' takes {} arguments, got {}",
+            invocation.ident().unwrap_or_default(), definition.params.len(), args.len(),
+        )));
+    }
+    if start == 2 {
+        out.push(line[..2].to_vec());
+    }
+    for body_line in &definition.body {
+        let mut expanded = Vec::new();
+        for token in body_line {
+            let param = token.ident().and_then(|name| definition.params.iter().position(|param| param == name));
+            match param {
+                Some(index) => expanded.extend_from_slice(args[index]),
+                None => expanded.push(token.clone()),
+            }
+        }
+        expand_line(expanded, macros, depth + 1, out)?;
+    }
+    Ok(())
+}
+
+// A sum of numbers and symbols, evaluated once every label is known
+#[derive(Clone, Debug)]
+struct Expr {
+    terms: Vec<(bool, Token)>,
+    line: usize,
+    column: usize,
+}
+
+impl Expr {
+    // `at` positions the error when the expression is missing entirely
+    fn parse(at: &Token, tokens: &[Token]) -> Result<Self, AsmError> {
+        let start = tokens.first().unwrap_or(at);
+        let mut terms = Vec::new();
+        let mut negative = false;
+        let mut expect_term = true;
+        for token in tokens {
+            match (&token.kind, expect_term) {
+                (Kind::Minus, true) => negative = !negative,
+                (Kind::Number(_), true) | (Kind::Ident(_), true) => {
+                    terms.push((negative, token.clone()));
+                    negative = false;
+                    expect_term = false;
+                }
+                (Kind::Plus, false) => expect_term = true,
+                (Kind::Minus, false) => {
+                    negative = true;
+                    expect_term = true;
+                }
+                _ => return Err(AsmError::at(token, "unexpected token in expression".to_string())),
+            }
+        }
+        if expect_term {
+            let token = tokens.last().unwrap_or(at);
+            return Err(AsmError::at(token, "expected a value".to_string()));
+        }
+        Ok(Self { terms, line: start.line, column: start.column })
+    }
+
+    fn eval(&self, symbols: &HashMap<String, i64>) -> Result<i64, AsmError> {
+        let mut total = 0;
+        for (negative, token) in &self.terms {
+            let value = match &token.kind {
+                Kind::Number(value) => *value,
+                Kind::Ident(name) => *symbols.get(name)
+                    .ok_or_else(|| AsmError::at(token, format!("unknown symbol '{}'", name)))?,
+                _ => unreachable!(),
+            };
+            total += if *negative { -value } else { value };
+        }
+        Ok(total)
+    }
+
+    // Evaluate and check the result fits the field it is encoded into
+    fn value(&self, symbols: &HashMap<String, i64>, min: i64, max: i64) -> Result<u16, AsmError> {
+        let value = self.eval(symbols)?;
+        if value < min || value > max {
+            return Err(AsmError::new(self.line, self.column, format!(
+                "value {} is out of range {}..={}", value, min, max,
+            )));
+        }
+        Ok(value as u16)
+    }
+}
+
+#[derive(Debug)]
+enum Operand {
+    V(u16),
+    // Vx - Vy, the register ranges of the XO-CHIP save and load
+    Range(u16, u16),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(Expr),
+    Value(Expr),
+}
+
+fn register(token: &Token) -> Option<u16> {
+    let name = token.ident()?;
+    let digit = name.strip_prefix('V').or_else(|| name.strip_prefix('v'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u16::from_str_radix(digit, 16).ok()
+}
+
+impl Operand {
+    fn parse(at: &Token, tokens: &[Token]) -> Result<Self, AsmError> {
+        if let [token] = tokens {
+            if let Some(x) = register(token) {
+                return Ok(Operand::V(x));
+            }
+            let name = token.ident().map(|name| name.to_ascii_uppercase());
+            let operand = match name.as_deref() {
+                Some("I") => Some(Operand::I),
+                Some("DT") => Some(Operand::Dt),
+                Some("ST") => Some(Operand::St),
+                Some("K") => Some(Operand::K),
+                Some("F") => Some(Operand::F),
+                Some("HF") => Some(Operand::Hf),
+                Some("B") => Some(Operand::B),
+                Some("R") => Some(Operand::R),
+                _ => None,
+            };
+            if let Some(operand) = operand {
+                return Ok(operand);
+            }
+        }
+        match tokens {
+            [Token { kind: Kind::LeftBracket, .. }, i, Token { kind: Kind::RightBracket, .. }] if i.is_keyword("I") =>
+                Ok(Operand::IndirectI),
+            [first, Token { kind: Kind::Minus, .. }, last] if register(first).is_some() && register(last).is_some() =>
+                Ok(Operand::Range(register(first).unwrap_or_default(), register(last).unwrap_or_default())),
+            [long, rest @ ..] if long.is_keyword("LONG") => Ok(Operand::Long(Expr::parse(long, rest)?)),
+            _ => Ok(Operand::Value(Expr::parse(at, tokens)?)),
+        }
+    }
+}
+
+enum Item {
+    Instruction { mnemonic: String, operands: Vec<Operand> },
+    Data { width: usize, values: Vec<Expr> },
+    Bytes(Vec<u8>),
+}
+
+struct Statement {
+    token: Token,
+    item: Item,
+}
+
+const MNEMONICS: [&str; 35] = [
+    "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU", "JP", "CALL", "SE",
+    "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP",
+    "SKNP", "SAVE", "LOAD", "PLANE", "PITCH", "DB", "DW", "SPRITE", "INCLUDE",
+];
+
+impl Statement {
+    fn parse(tokens: &[Token], include_dir: &Path) -> Result<Self, AsmError> {
+        let token = tokens[0].clone();
+        let mnemonic = match token.ident() {
+            Some(name) if MNEMONICS.contains(&name.to_ascii_uppercase().as_str()) => name.to_ascii_uppercase(),
+            Some(name) => return Err(AsmError::at(&token, format!("unknown instruction '{}'", name))),
+            None => return Err(AsmError::at(&token, "expected an instruction".to_string())),
+        };
+        let args = split_commas(&tokens[1..]);
+        let item = match mnemonic.as_str() {
+            "DB" | "DW" => {
+                let values = args.iter().map(|arg| Expr::parse(&token, arg)).collect::<Result<Vec<_>, _>>()?;
+                if values.is_empty() {
+                    return Err(AsmError::at(&token, "expected a value".to_string()));
+                }
+                Item::Data { width: if mnemonic == "DB" { 1 } else { 2 }, values }
+            }
+            "SPRITE" => Item::Bytes(sprite(&token, &args)?),
+            "INCLUDE" => match args.as_slice() {
+                [[Token { kind: Kind::Str(file), .. }]] => {
+                    let path = include_dir.join(file);
+                    let data = fs::read(&path).map_err(|e| {
+                        AsmError::at(&tokens[1], format!("could not read '{}': {}", path.display(), e))
+                    })?;
+                    Item::Bytes(data)
+                }
+                _ => return Err(AsmError::at(&token, "expected a file name in quotes".to_string())),
+            },
+            _ => {
+                let operands = args.iter().map(|arg| Operand::parse(&token, arg)).collect::<Result<Vec<_>, _>>()?;
+                Item::Instruction { mnemonic, operands }
+            }
+        };
+        Ok(Self { token, item })
+    }
+
+    fn size(&self) -> usize {
+        match &self.item {
+            Item::Instruction { operands, .. } if operands.iter().any(|op| matches!(op, Operand::Long(_))) => 4,
+            Item::Instruction { .. } => 2,
+            Item::Data { width, values } => width * values.len(),
+            Item::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    fn encode(&self, symbols: &HashMap<String, i64>, rom: &mut Vec<u8>) -> Result<(), AsmError> {
+        let (mnemonic, operands) = match &self.item {
+            Item::Instruction { mnemonic, operands } => (mnemonic, operands),
+            Item::Data { width: 1, values } => {
+                for value in values {
+                    rom.push(value.value(symbols, -128, 0xFF)? as u8);
+                }
+                return Ok(());
+            }
+            Item::Data { values, .. } => {
+                for value in values {
+                    rom.extend_from_slice(&value.value(symbols, -0x8000, 0xFFFF)?.to_be_bytes());
+                }
+                return Ok(());
+            }
+            Item::Bytes(bytes) => {
+                rom.extend_from_slice(bytes);
+                return Ok(());
+            }
+        };
+
+        let byte = |e: &Expr| e.value(symbols, -128, 0xFF).map(|v| v & 0xFF);
+        let addr = |e: &Expr| e.value(symbols, 0, 0xFFF);
+        let nibble = |e: &Expr| e.value(symbols, 0, 0xF);
+        let xy = |opcode: u16, x: u16, y: u16| opcode | (x << 8) | (y << 4);
+
+        use Operand::*;
+        let opcode = match (mnemonic.as_str(), operands.as_slice()) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("AUDIO", []) => 0xF002,
+            ("SCD", [Value(n)]) => 0x00C0 | nibble(n)?,
+            ("SCU", [Value(n)]) => 0x00D0 | nibble(n)?,
+            ("JP", [Value(a)]) => 0x1000 | addr(a)?,
+            ("JP", [V(0), Value(a)]) => 0xB000 | addr(a)?,
+            ("CALL", [Value(a)]) => 0x2000 | addr(a)?,
+            ("SE", [V(x), Value(k)]) => 0x3000 | (x << 8) | byte(k)?,
+            ("SE", [V(x), V(y)]) => xy(0x5000, *x, *y),
+            ("SNE", [V(x), Value(k)]) => 0x4000 | (x << 8) | byte(k)?,
+            ("SNE", [V(x), V(y)]) => xy(0x9000, *x, *y),
+            ("LD", [V(x), Value(k)]) => 0x6000 | (x << 8) | byte(k)?,
+            ("LD", [V(x), V(y)]) => xy(0x8000, *x, *y),
+            ("LD", [I, Value(a)]) => 0xA000 | addr(a)?,
+            ("LD", [I, Long(a)]) => {
+                rom.extend_from_slice(&[0xF0, 0x00]);
+                a.value(symbols, 0, 0xFFFF)?
+            }
+            ("LD", [V(x), Dt]) => 0xF007 | (x << 8),
+            ("LD", [V(x), K]) => 0xF00A | (x << 8),
+            ("LD", [Dt, V(x)]) => 0xF015 | (x << 8),
+            ("LD", [St, V(x)]) => 0xF018 | (x << 8),
+            ("LD", [F, V(x)]) => 0xF029 | (x << 8),
+            ("LD", [Hf, V(x)]) => 0xF030 | (x << 8),
+            ("LD", [B, V(x)]) => 0xF033 | (x << 8),
+            ("LD", [IndirectI, V(x)]) => 0xF055 | (x << 8),
+            ("LD", [V(x), IndirectI]) => 0xF065 | (x << 8),
+            ("LD", [R, V(x)]) => 0xF075 | (x << 8),
+            ("LD", [V(x), R]) => 0xF085 | (x << 8),
+            ("ADD", [V(x), Value(k)]) => 0x7000 | (x << 8) | byte(k)?,
+            ("ADD", [V(x), V(y)]) => xy(0x8004, *x, *y),
+            ("ADD", [I, V(x)]) => 0xF01E | (x << 8),
+            ("OR", [V(x), V(y)]) => xy(0x8001, *x, *y),
+            ("AND", [V(x), V(y)]) => xy(0x8002, *x, *y),
+            ("XOR", [V(x), V(y)]) => xy(0x8003, *x, *y),
+            ("SUB", [V(x), V(y)]) => xy(0x8005, *x, *y),
+            ("SHR", [V(x)]) => xy(0x8006, *x, *x),
+            ("SHR", [V(x), V(y)]) => xy(0x8006, *x, *y),
+            ("SUBN", [V(x), V(y)]) => xy(0x8007, *x, *y),
+            ("SHL", [V(x)]) => xy(0x800E, *x, *x),
+            ("SHL", [V(x), V(y)]) => xy(0x800E, *x, *y),
+            ("RND", [V(x), Value(k)]) => 0xC000 | (x << 8) | byte(k)?,
+            ("DRW", [V(x), V(y), Value(n)]) => xy(0xD000, *x, *y) | nibble(n)?,
+            ("SKP", [V(x)]) => 0xE09E | (x << 8),
+            ("SKNP", [V(x)]) => 0xE0A1 | (x << 8),
+            ("SAVE", [Range(x, y)]) => xy(0x5002, *x, *y),
+            ("LOAD", [Range(x, y)]) => xy(0x5003, *x, *y),
+            ("PLANE", [Value(n)]) => 0xF001 | (nibble(n)? << 8),
+            ("PITCH", [V(x)]) => 0xF03A | (x << 8),
+            _ => return Err(AsmError::at(&self.token, format!("invalid operands for {}", mnemonic))),
+        };
+        rom.extend_from_slice(&opcode.to_be_bytes());
+        Ok(())
+    }
+}
+
+// Each string is a row of 8 or 16 pixels: '#', '1' or 'X' are set, '.', '0' or '_' are clear
+fn sprite(at: &Token, args: &[&[Token]]) -> Result<Vec<u8>, AsmError> {
+    if args.is_empty() {
+        return Err(AsmError::at(at, "expected sprite rows in quotes".to_string()));
+    }
+    let mut bytes = Vec::new();
+    for arg in args {
+        let (row, token) = match arg {
+            [token @ Token { kind: Kind::Str(row), .. }] => (row, token),
+            _ => return Err(AsmError::at(arg.first().unwrap_or(at), "expected a sprite row in quotes".to_string())),
+        };
+        if row.is_empty() || row.len() & 7 != 0 {
+            return Err(AsmError::at(token, format!("sprite rows are 8 or 16 pixels wide, got {}", row.len())));
+        }
+        let mut bits = Vec::with_capacity(row.len());
+        for c in row.chars() {
+            match c {
+                '#' | '1' | 'X' | 'x' => bits.push(1),
+                '.' | '0' | '_' => bits.push(0),
+                c => return Err(AsmError::at(token, format!("unexpected '{}' in sprite row", c))),
+            }
+        }
+        for chunk in bits.chunks(8) {
+            bytes.push(chunk.iter().fold(0, |byte, bit| (byte << 1) | bit));
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+    use std::env;
+    use std::path::PathBuf;
+
+    fn asm(source: &str) -> Vec<u8> {
+        assemble(source, Path::new(".")).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn asm_error(source: &str) -> AsmError {
+        assemble(source, Path::new(".")).expect_err("source should not assemble")
+    }
+
+    #[test]
+    fn labels_resolve_backwards_and_forwards() {
+        let rom = asm("
+            start:  CALL routine
+                    JP start
+            routine:
+                    RET
+        ");
+        assert_eq!(rom, [0x22, 0x04, 0x12, 0x00, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn constants_can_be_used_in_expressions() {
+        let rom = asm("
+            ROWS = 5
+            TALL = ROWS + 3 - 1
+                    DRW V0, V1, ROWS
+                    LD V2, TALL
+                    LD V3, -TALL + 8
+        ");
+        assert_eq!(rom, [0xD0, 0x15, 0x62, 0x07, 0x63, 0x01]);
+        assert_eq!(asm_error("A = 1\nA = 2\n").message, "'A' is already defined");
+    }
+
+    #[test]
+    fn macros_expand_with_their_arguments() {
+        let rom = asm("
+            MACRO swap a, b
+                LD VF, a
+                LD a, b
+                LD b, VF
+            ENDM
+            MACRO rotate a, b, c
+                swap a, b
+                swap b, c
+            ENDM
+                    rotate V1, V2, V3
+        ");
+        assert_eq!(rom, [
+            0x8F, 0x10, 0x81, 0x20, 0x82, 0xF0,
+            0x8F, 0x20, 0x82, 0x30, 0x83, 0xF0,
+        ]);
+    }
+
+    #[test]
+    fn macros_check_their_argument_count() {
+        let e = asm_error("MACRO twice a\n  ADD a, 1\n  ADD a, 1\nENDM\n  twice V1, V2\n");
+        assert_eq!((e.line, e.column), (5, 3));
+        assert_eq!(e.message, "macro 'twice' takes 1 arguments, got 2");
+        assert_eq!(asm_error("MACRO loop\n  loop\nENDM\n  loop\n").message, "macros nested too deeply");
+        assert_eq!(asm_error("MACRO open\n  CLS\n").message, "macro 'open' is missing ENDM");
+    }
+
+    #[test]
+    fn sprites_pack_rows_into_bytes() {
+        assert_eq!(asm(r##"SPRITE "#..#....", "11110000", "X_X_X_X_""##), [0x90, 0xF0, 0xAA]);
+        assert_eq!(asm(r#"SPRITE "1111111111111111""#), [0xFF, 0xFF]);
+        assert!(asm_error(r##"SPRITE "#..#""##).message.contains("8 or 16 pixels"));
+    }
+
+    #[test]
+    fn data_is_range_checked() {
+        assert_eq!(asm("DB 0, 255, -1, -128\nDW 0x1234, -1\n"), [0, 0xFF, 0xFF, 0x80, 0x12, 0x34, 0xFF, 0xFF]);
+        assert_eq!(asm_error("DB 256").message, "value 256 is out of range -128..=255");
+        assert_eq!(asm_error("DB -129").message, "value -129 is out of range -128..=255");
+        assert_eq!(asm_error("DW 0x10000").message, "value 65536 is out of range -32768..=65535");
+        assert_eq!(asm_error("LD I, 0x1000").message, "value 4096 is out of range 0..=4095");
+    }
+
+    #[test]
+    fn long_loads_take_two_words() {
+        let rom = asm("
+                    LD I, LONG data
+                    JP data
+            data:   DB 1
+        ");
+        assert_eq!(rom, [0xF0, 0x00, 0x02, 0x06, 0x12, 0x06, 0x01]);
+        assert_eq!(asm("LD I, LONG 0xFFFF"), [0xF0, 0x00, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn include_reads_files_next_to_the_source() {
+        let dir = env::temp_dir().join(format!("chip8-asm-include-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tiles.bin"), [0xDE, 0xAD]).unwrap();
+        let rom = assemble("CLS\nINCLUDE \"tiles.bin\"\n", &dir);
+        let missing = assemble("INCLUDE \"missing.bin\"\n", &dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rom.unwrap(), [0x00, 0xE0, 0xDE, 0xAD]);
+        let e = missing.expect_err("missing file");
+        assert_eq!((e.line, e.column), (1, 9));
+    }
+
+    #[test]
+    fn errors_report_line_and_column() {
+        let e = asm_error("CLS\n  JP nowhere\n");
+        assert_eq!(e.to_string(), "2:6: unknown symbol 'nowhere'");
+        let e = asm_error("CLS\n\n    FOO V1\n");
+        assert_eq!(e.to_string(), "3:5: unknown instruction 'FOO'");
+        let e = asm_error("  LD V1, V2, V3\n");
+        assert_eq!(e.to_string(), "1:3: invalid operands for LD");
+        let e = asm_error("  LD V1, $\n");
+        assert_eq!(e.to_string(), "1:10: unexpected character '$'");
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_rom() {
+        for name in ["ibm.ch8", "maze.ch8", "particle.ch8", "Airplane.ch8", "BC_test.ch8", "test_opcode.ch8"] {
+            let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "roms", name].iter().collect();
+            let rom = fs::read(path).unwrap();
+            // Drop the address and raw byte columns of the listing
+            let source: String = disasm::listing(&rom, PROGRAM_START as u16)
+                .lines()
+                .map(|line| if line.ends_with(':') { line } else { &line[20..] })
+                .map(|line| format!("{}\n", line))
+                .collect();
+            assert_eq!(asm(&source), rom, "{}", name);
+        }
+    }
+}
@@ -1,66 +1,249 @@
 extern crate cpal;
 
-use cpal::{Device, Stream};
+use cpal::Stream;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crate::cpu::Cpu;
+use crate::tone_queue::{ToneChange, ToneQueue};
+use std::f32::consts::PI;
+use std::io;
+use std::sync::Arc;
 
-pub(crate) struct Opt {
-    device: cpal::Device,
+// What the beeper plays, as reported to an AudioSink every frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tone {
+    // Whether the sound timer is running
+    pub on: bool,
+    // XO-CHIP 1-bit audio pattern, the plain beep is used while there is none
+    pub pattern: Option<[u8; 16]>,
+    pub pitch: u8,
 }
 
-impl Opt {
-    pub(crate) fn new() -> Self {
-        let available_hosts = cpal::available_hosts();
-        let mut default_out;
-        for host_id in available_hosts {
-            let host = cpal::host_from_id(host_id).unwrap();
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            on: false,
+            pattern: None,
+            pitch: 64,
+        }
+    }
+}
 
-            default_out = host.default_output_device().map(|e| e.name().unwrap());
-            println!("  Default Output Device:\n    {:?}", default_out);
+impl Tone {
+    // What the beeper of `cpu` is playing
+    pub fn of(cpu: &Cpu) -> Self {
+        Self {
+            on: cpu.sound_active(),
+            pattern: cpu.audio_pattern(),
+            pitch: cpu.pitch(),
         }
-        let host = cpal::default_host();
-        let default_device = host.default_output_device().unwrap();
-        Opt{ device: default_device}
     }
 
-    pub fn beep(self) -> Stream{
+    // Pattern bits played per second, 4000 Hz at the default pitch of 64
+    pub fn pattern_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
 
-        let device = self.device;
+pub const WAVEFORMS: [&str; 4] = ["square", "triangle", "sawtooth", "sine"];
 
-        let config = device.default_output_config().unwrap();
+impl Waveform {
+    // Look up a waveform by one of the names in WAVEFORMS
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            "sawtooth" | "saw" => Some(Waveform::Sawtooth),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+
+    // The value between -1 and 1 at `phase`, which runs from 0 to 1 over one period
+    pub fn sample(&self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+        }
+    }
+}
+
+// How the beeper sounds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioSettings {
+    // Pitch of the plain beep in Hz; XO-CHIP patterns set their own
+    pub frequency: f32,
+    pub waveform: Waveform,
+    // Output level from 0 to 1
+    pub volume: f32,
+    // Seconds to fade in when the sound timer starts and out when it stops,
+    // so the tone does not start or end with a click
+    pub attack: f32,
+    pub release: f32,
+}
 
-        match config.sample_format() {
-            cpal::SampleFormat::F32 => Opt::run::<f32>(&device, &config.into()),
-            cpal::SampleFormat::I16 => Opt::run::<i16>(&device, &config.into()),
-            cpal::SampleFormat::U16 => Opt::run::<u16>(&device, &config.into()),
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            frequency: 780.0,
+            waveform: Waveform::Sine,
+            volume: 0.5,
+            attack: 0.005,
+            release: 0.005,
         }
     }
+}
+
+// Generates the beeper output one sample at a time
+pub struct Beeper {
+    settings: AudioSettings,
+    sample_rate: f32,
+    // Position within the current period of the beep, from 0 to 1
+    phase: f32,
+    // Position within the 128 bit audio pattern
+    pattern_position: f32,
+    // Envelope level from 0 to 1, and how far it moves per sample
+    gain: f32,
+    attack_step: f32,
+    release_step: f32,
+}
+
+impl Beeper {
+    pub fn new(settings: AudioSettings, sample_rate: f32) -> Self {
+        let step = |seconds: f32| if seconds > 0.0 { 1.0 / (seconds * sample_rate) } else { 1.0 };
+        Self {
+            settings,
+            sample_rate,
+            phase: 0.0,
+            pattern_position: 0.0,
+            gain: 0.0,
+            attack_step: step(settings.attack),
+            release_step: step(settings.release),
+        }
+    }
+
+    // True once the tone has faded out completely
+    pub fn is_silent(&self) -> bool {
+        self.gain == 0.0
+    }
+
+    pub fn next_sample(&mut self, tone: &Tone) -> f32 {
+        self.gain = if tone.on {
+            (self.gain + self.attack_step).min(1.0)
+        } else {
+            (self.gain - self.release_step).max(0.0)
+        };
+
+        let value = match tone.pattern {
+            Some(pattern) => {
+                self.pattern_position = (self.pattern_position + tone.pattern_rate() / self.sample_rate) % 128.0;
+                let bit = self.pattern_position as usize;
+                if (pattern[bit / 8] >> (7 - bit % 8)) & 1 != 0 { 1.0 } else { -1.0 }
+            }
+            None => {
+                // The phase wraps within one period, so the wave stays continuous
+                self.phase = (self.phase + self.settings.frequency / self.sample_rate).fract();
+                self.settings.waveform.sample(self.phase)
+            }
+        };
+        value * self.gain * self.settings.volume
+    }
+}
+
+// Receives the beeper output of the emulator, one 60 Hz frame at a time
+pub trait AudioSink {
+    // What the beeper plays for the duration of one emulated frame
+    fn frame(&mut self, tone: &Tone);
+
+    // Called once the run is over
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Discards the sound, for muted runs and runs without an output device
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn frame(&mut self, _tone: &Tone) {}
+}
+
+// Frames the sound trails the emulator by, so the callback rarely finds the
+// next frame missing
+const LATENCY: f64 = 2.0;
+// How far the sound may fall behind, e.g. while fast-forwarding, before it
+// skips ahead instead of catching up
+const MAX_LAG: f64 = 8.0;
+
+// Plays the sound on the default output device. Every change of the beeper is
+// stamped with the emulated frame it happens in and queued for the audio
+// callback, which starts it exactly on that frame's first sample. A sound timer
+// of N therefore always sounds for N/60 s, however the frames were batched.
+pub struct CpalSink {
+    queue: Arc<ToneQueue>,
+    // Frames reported so far, and the last tone queued
+    frame: u64,
+    last: Option<Tone>,
+    _stream: Stream,
+}
+
+impl CpalSink {
+    pub fn open(settings: AudioSettings) -> Result<Self, String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| "no audio output device".to_string())?;
+        let config = device.default_output_config().map_err(|e| e.to_string())?;
+        let queue = Arc::new(ToneQueue::new());
+
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => CpalSink::run::<f32>(&device, &config.into(), settings, Arc::clone(&queue)),
+            cpal::SampleFormat::I16 => CpalSink::run::<i16>(&device, &config.into(), settings, Arc::clone(&queue)),
+            cpal::SampleFormat::U16 => CpalSink::run::<u16>(&device, &config.into(), settings, Arc::clone(&queue)),
+        }?;
+        Ok(Self { queue, frame: 0, last: None, _stream: stream })
+    }
 
-    fn run<T>(device: &cpal::Device, config: &cpal::StreamConfig) -> Stream
+    fn run<T>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        settings: AudioSettings,
+        queue: Arc<ToneQueue>,
+    ) -> Result<Stream, String>
         where
             T: cpal::Sample,
     {
-        let sample_rate = config.sample_rate.0 as f32;
         let channels = config.channels as usize;
-
-        // Produce a sinusoid of maximum amplitude.
-        let mut sample_clock = 0f32;
-        let mut next_value = move || {
-            sample_clock = (sample_clock + 1.0) % sample_rate;
-            (sample_clock * 780.0 * 2.0 * std::f32::consts::PI / sample_rate).sin()
+        let sample_rate = config.sample_rate.0 as f32;
+        let mut player = Player {
+            queue,
+            beeper: Beeper::new(settings, sample_rate),
+            tone: Tone::default(),
+            position: 0.0,
+            step: 60.0 / sample_rate as f64,
         };
 
         let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
+        // The stream keeps running, the envelope fades the tone in and out
         let stream = device.build_output_stream(
             config,
             move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                Opt::write_data(data, channels, &mut next_value)
+                CpalSink::write_data(data, channels, &mut || player.next_sample())
             },
             err_fn,
-        ).unwrap();
-        stream.pause();
+        ).map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
 
-        stream
+        Ok(stream)
     }
 
     fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> f32)
@@ -76,4 +259,47 @@ impl Opt {
     }
 }
 
+impl AudioSink for CpalSink {
+    // Only changes are queued. One that does not fit is retried next frame.
+    fn frame(&mut self, tone: &Tone) {
+        if self.last != Some(*tone) && self.queue.push(ToneChange { frame: self.frame, tone: *tone }) {
+            self.last = Some(*tone);
+        }
+        self.frame += 1;
+        self.queue.set_frames(self.frame);
+    }
+}
+
+// The audio callback's side of a CpalSink, which keeps its own clock in
+// emulated frames
+struct Player {
+    queue: Arc<ToneQueue>,
+    beeper: Beeper,
+    tone: Tone,
+    // Emulated time of the next sample, in frames
+    position: f64,
+    // Frames per sample
+    step: f64,
+}
 
+impl Player {
+    fn next_sample(&mut self) -> f32 {
+        let end = self.queue.frames() as f64 - LATENCY;
+        if end - self.position > MAX_LAG {
+            self.position = end;
+        }
+        while let Some(change) = self.queue.peek() {
+            if change.frame as f64 > self.position {
+                break;
+            }
+            self.tone = change.tone;
+            self.queue.pop();
+        }
+        // When the emulator has not reported the next frame yet the clock
+        // waits, holding the current tone
+        if self.position + self.step <= end {
+            self.position += self.step;
+        }
+        self.beeper.next_sample(&self.tone)
+    }
+}
@@ -0,0 +1,36 @@
+use anyhow::{anyhow, Context};
+use chip8::{assembler, octo};
+use clap::Parser;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Assemble CHIP-8 source, or compile Octo source ending in .8o, into a ROM
+#[derive(Parser)]
+#[clap(version, about)]
+struct Args {
+    /// Path to the assembly source
+    source: PathBuf,
+
+    /// Where to write the ROM, defaults to the source with a .ch8 extension
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let source = fs::read_to_string(&args.source)
+        .with_context(|| format!("could not read '{}'", args.source.display()))?;
+    let include_dir = args.source.parent().unwrap_or_else(|| Path::new("."));
+    let rom = if args.source.extension().is_some_and(|extension| extension == "8o") {
+        octo::compile(&source)
+    } else {
+        assembler::assemble(&source, include_dir)
+    };
+    let rom = rom.map_err(|e| anyhow!("{}:{}", args.source.display(), e))?;
+
+    let output = args.output.clone().unwrap_or_else(|| args.source.with_extension("ch8"));
+    fs::write(&output, &rom)
+        .with_context(|| format!("could not write '{}'", output.display()))?;
+    println!("wrote {} bytes to {}", rom.len(), output.display());
+    Ok(())
+}
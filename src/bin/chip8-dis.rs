@@ -0,0 +1,31 @@
+use anyhow::Context;
+use chip8::disasm;
+use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
+
+/// Disassemble a CHIP-8 ROM into an annotated listing
+#[derive(Parser)]
+#[clap(version, about)]
+struct Args {
+    /// Path to the ROM to disassemble
+    rom: PathBuf,
+
+    /// Address the ROM is loaded at, as hex
+    #[clap(long, default_value = "200", value_parser = parse_address)]
+    base: u16,
+}
+
+fn parse_address(value: &str) -> Result<u16, String> {
+    let hex = value.trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).map_err(|_| format!("expected a hex address, got '{}'", value))
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let rom = fs::read(&args.rom)
+        .with_context(|| format!("could not read ROM '{}'", args.rom.display()))?;
+    println!("; {} loaded at 0x{:03X}", args.rom.display(), args.base);
+    print!("{}", disasm::listing(&rom, args.base));
+    Ok(())
+}
@@ -1,30 +1,119 @@
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use crate::memory::Memory;
-use crate::cpu::Cpu;
+use crate::movie::{self, Header, Movie, Session};
+use crate::cpu::{Cpu, SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::audio::{AudioSettings, AudioSink, CpalSink, NullSink, Tone};
+use crate::debugger::Debugger;
+use crate::dump;
+use crate::error::Chip8Error;
+use crate::headless::{self, Stop};
+use crate::keypad::{Hotkeys, Keymap};
+use crate::octo;
+use crate::palette::{Palette, THEMES};
+use crate::persistence::{Display, Persistence};
+use crate::quirks::Quirks;
+use crate::rewind::Rewind;
+use crate::rng::Random;
+use crate::savestate;
+use crate::scheduler::Scheduler;
 use std::{thread, time};
-use pixels::{Error, Pixels, SurfaceTexture};
+use pixels::{Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
-use winit::event::{Event, VirtualKeyCode};
+use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::platform::unix::WindowExtUnix;
-use winit::window::WindowBuilder;
+use winit::window::{Fullscreen, WindowBuilder};
 use winit_input_helper::WinitInputHelper;
 
-const WIDTH: u32 = 64;
-const HEIGHT: u32 = 32;
+const WIDTH: u32 = SCREEN_WIDTH as u32;
+const HEIGHT: u32 = SCREEN_HEIGHT as u32;
+
+// How the frontend runs and presents the interpreter
+pub struct Settings {
+    pub instructions_per_frame: u32,
+    pub scale: u32,
+    pub palette: Palette,
+    // How the framebuffer is smoothed over frames to hide sprite flicker
+    pub persistence: Persistence,
+    pub mute: bool,
+    pub audio: AudioSettings,
+    pub fullscreen: bool,
+    pub quirks: Quirks,
+    // How far back the rewind key can go, 0 disables rewinding
+    pub rewind_seconds: u32,
+    // Start paused with the debugger REPL on stdin
+    pub debug: bool,
+    // Emulation speed multiplier, 1.0 runs at 60 frames per second
+    pub speed: f64,
+    // Seed for CXKK, None seeds from the operating system
+    pub seed: Option<u32>,
+    // Generate random numbers with the COSMAC VIP interpreter's method
+    pub vip_random: bool,
+    // Host keys for the CHIP-8 keypad
+    pub keymap: Keymap,
+    // Host keys for the emulator controls
+    pub hotkeys: Hotkeys,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            instructions_per_frame: 10,
+            scale: 10,
+            palette: THEMES[0].1,
+            persistence: Persistence::Off,
+            mute: false,
+            audio: AudioSettings::default(),
+            fullscreen: false,
+            quirks: Quirks::default(),
+            rewind_seconds: 10,
+            debug: false,
+            speed: 1.0,
+            seed: None,
+            vip_random: false,
+            keymap: Keymap::default(),
+            hotkeys: Hotkeys::default(),
+        }
+    }
+}
+
+// Number of save state slots reachable from the hotkeys
+const STATE_SLOTS: u8 = 10;
+// Frames run per 60 Hz frame while fast-forwarding
+const TURBO_FRAMES: u32 = 4;
 
 pub struct CHIP8 {
     mem: Memory,
     cpu: Cpu,
+    settings: Settings,
+    rom_path: Option<PathBuf>,
+    // The program as loaded, kept to restart it
+    rom: Vec<u8>,
+    rom_hash: u64,
+    slot: u8,
+    // Keypad input being recorded or replayed
+    movie: Option<Session>,
 }
 
 impl CHIP8 {
     pub fn new() -> Self {
+        CHIP8::with_settings(Settings::default())
+    }
+
+    pub fn with_settings(settings: Settings) -> Self {
+        let mut cpu = Cpu::with_quirks(settings.quirks);
+        cpu.set_rng(CHIP8::random(&settings));
         Self {
-            mem: Memory::new(),
-            cpu: Cpu::new(),
+            mem: Memory::for_quirks(settings.quirks),
+            cpu,
+            settings,
+            rom_path: None,
+            rom: Vec::new(),
+            rom_hash: 0,
+            slot: 0,
+            movie: None,
         }
     }
 
@@ -32,37 +121,122 @@ impl CHIP8 {
         Self {
             mem,
             cpu,
+            settings: Settings::default(),
+            rom_path: None,
+            rom: Vec::new(),
+            rom_hash: 0,
+            slot: 0,
+            movie: None,
         }
     }
 
-    pub fn load_rom(&mut self, path: &str) -> io::Result<()> {
-        let mut rom_file = File::open(path)?;
+    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.rom_path = Some(path.as_ref().to_path_buf());
+        let mut rom_file = File::open(&path)?;
         let mut rom_data = Vec::new();
 
-        let rom_size = rom_file.read_to_end(&mut rom_data)?;
+        rom_file.read_to_end(&mut rom_data)?;
 
-        // Load the ROM into main memory at 0x200
-        for i in 0..rom_size {
-            self.mem.set(0x200 + i,rom_data[i]);
+        // Octo sources are compiled on the fly
+        if path.as_ref().extension().is_some_and(|extension| extension == "8o") {
+            let source = String::from_utf8(rom_data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            rom_data = octo::compile(&source)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         }
 
+        // Load the ROM into main memory at 0x200
+        self.rom_hash = movie::rom_hash(&rom_data);
+        self.mem.load_rom(&rom_data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.rom = rom_data;
         Ok(())
     }
 
-    pub fn start(mut self) {
-        let mut ten_millis = time::Duration::from_millis(16);
-        let mut now = time::Instant::now();
+    // Restart the program on a freshly powered-on machine
+    pub fn reset(&mut self) {
+        self.mem = Memory::for_quirks(self.settings.quirks);
+        self.mem.load_font();
+        self.mem.load_rom(&self.rom).expect("the ROM fit in memory when it was loaded");
+        self.cpu = Cpu::with_quirks(self.settings.quirks);
+        self.cpu.set_rng(CHIP8::random(&self.settings));
+    }
+
+    // Record the keypad to a movie written when the run ends. The seed is
+    // fixed first so the recording can be replayed exactly.
+    pub fn record<P: Into<PathBuf>>(&mut self, path: P) {
+        let seed = *self.settings.seed.get_or_insert_with(rand::random);
+        self.cpu.set_rng(CHIP8::random(&self.settings));
+        let header = Header {
+            rom_hash: self.rom_hash,
+            seed,
+            vip_random: self.settings.vip_random,
+            quirks: self.settings.quirks,
+            instructions_per_frame: self.settings.instructions_per_frame,
+        };
+        self.movie = Some(Session::Recording { movie: Movie::new(header), path: path.into() });
+    }
 
+    // Replay a movie from the start, with the settings it was recorded with
+    pub fn replay(&mut self, movie: Movie) -> io::Result<()> {
+        if movie.header.rom_hash != self.rom_hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the movie was recorded with a different ROM"));
+        }
+        self.settings.quirks = movie.header.quirks;
+        self.settings.seed = Some(movie.header.seed);
+        self.settings.vip_random = movie.header.vip_random;
+        self.settings.instructions_per_frame = movie.header.instructions_per_frame;
+        self.reset();
+        self.movie = Some(Session::Playback { movie, frame: 0 });
+        Ok(())
+    }
+
+    // Write out the movie being recorded, if any
+    pub fn finish_movie(&self) -> io::Result<()> {
+        match &self.movie {
+            Some(session) => session.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn random(settings: &Settings) -> Random {
+        if settings.vip_random {
+            Random::vip(settings.seed)
+        } else {
+            Random::xorshift(settings.seed)
+        }
+    }
+
+    pub fn start(mut self) {
+        // Without an output device the emulator carries on silently
+        let mut audio: Box<dyn AudioSink> = if self.settings.mute {
+            Box::new(NullSink)
+        } else {
+            match CpalSink::open(self.settings.audio) {
+                Ok(sink) => Box::new(sink),
+                Err(e) => {
+                    eprintln!("could not open the audio device, running without sound: {}", e);
+                    Box::new(NullSink)
+                }
+            }
+        };
 
         let event_loop = EventLoop::new();
         let mut input = WinitInputHelper::new();
         let window = {
+            let scale = self.settings.scale as f64;
             let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
-            let scaled_size = LogicalSize::new(WIDTH as f64 * 10.0, HEIGHT as f64 * 10.0);
+            let scaled_size = LogicalSize::new(WIDTH as f64 * scale, HEIGHT as f64 * scale);
+            let fullscreen = if self.settings.fullscreen {
+                Some(Fullscreen::Borderless(None))
+            } else {
+                None
+            };
             WindowBuilder::new()
                 .with_title("Chip 8 emulate")
                 .with_inner_size(scaled_size)
                 .with_min_inner_size(size)
+                .with_fullscreen(fullscreen)
                 .build(&event_loop)
                 .unwrap()
         };
@@ -73,131 +247,330 @@ impl CHIP8 {
             Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
         };
 
+        // Keys only reach the program while the window has focus
+        let mut focused = true;
+        // The built-in theme last picked with the theme hotkey
+        let mut theme: Option<usize> = None;
+        // Toggled with the pause hotkey
+        let mut paused = false;
+        // Set once the program faults; the last frame stays on screen
+        let mut halted = false;
+        // The pixel buffer follows the lores/hires display mode
+        let mut resolution = (self.cpu.width(), self.cpu.height());
+        // The picture shown, built up at every vertical blank
+        let mut display = Display::new(self.settings.persistence);
+        // Emulation runs in whole 60 Hz frames, independent of the redraw rate
+        let mut scheduler = Scheduler::with_speed(self.settings.speed);
+        // Instructions still to run in the current frame, kept when the debugger
+        // stops the machine part way through one
+        let mut budget = 0;
+        // Holding the rewind hotkey plays the recorded frames backwards
+        let mut rewind = Rewind::with_seconds(self.settings.rewind_seconds);
+        // The debugger REPL reads stdin while the window keeps showing the framebuffer
+        let mut debugger = if self.settings.debug {
+            println!("debugger attached, type 'help' for commands");
+            let mut debugger = Debugger::new(false);
+            debugger.pause("paused at start", &self.cpu, &self.mem);
+            Some((debugger, Debugger::spawn_repl()))
+        } else {
+            None
+        };
+
+        let hotkeys = self.settings.hotkeys;
 
         event_loop.run(move |event, _, control_flow| {
-            if now.elapsed() >= ten_millis {
-                now = time::Instant::now();
-                self.cpu.timer();
+            if let Some((debugger, commands)) = &mut debugger {
+                while let Ok(command) = commands.try_recv() {
+                    debugger.handle(command, &self.cpu, &mut self.mem);
+                }
+            }
+
+            if let Event::WindowEvent { event: WindowEvent::Focused(focus), .. } = event {
+                focused = focus;
+            }
+            if let Event::LoopDestroyed = event {
+                if let Err(e) = self.finish_movie() {
+                    eprintln!("could not save the movie: {}", e);
+                }
+                return;
             }
             if let Event::RedrawRequested(_) = event {
-                self.cpu.cycle(&mut self.mem);
-                self.cpu.draw(pixels.get_frame());
-
-               if pixels
-                   .render()
-                   // .map_err(|e| error!("pixels.render() failed: {}", e))
-                   .is_err()
-               {
-                   *control_flow = ControlFlow::Exit;
-                   return;
-               }
-           }
+                if resolution != (self.cpu.width(), self.cpu.height()) {
+                    resolution = (self.cpu.width(), self.cpu.height());
+                    pixels.resize_buffer(resolution.0 as u32, resolution.1 as u32);
+                }
+                display.draw(&self.cpu, pixels.get_frame(), &self.settings.palette);
+
+                if pixels
+                    .render()
+                    // .map_err(|e| error!("pixels.render() failed: {}", e))
+                    .is_err()
+                {
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+            }
             // Handle input events
             if input.update(&event) {
                 // Close events
-                if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
+                if input.key_pressed(hotkeys.quit) || input.quit() {
                     *control_flow = ControlFlow::Exit;
                     return;
                 }
 
+                // Jumping around in time would desynchronise a movie
+                let rewinding = input.key_held(hotkeys.rewind) && self.movie.is_none();
+
+                if input.key_pressed(hotkeys.pause) {
+                    paused = !paused;
+                    println!("{}", if paused { "paused" } else { "resumed" });
+                }
+                if input.key_pressed(hotkeys.reset) && self.movie.is_some() {
+                    eprintln!("the program cannot be reset while a movie is recorded or replayed");
+                } else if input.key_pressed(hotkeys.reset) {
+                    self.reset();
+                    halted = false;
+                    budget = 0;
+                    if let Some((debugger, _)) = &debugger {
+                        debugger.attach(&mut self.mem);
+                    }
+                    println!("reset");
+                }
+
+                // Cycle through the built-in themes, starting from the first
+                let mut recolored = false;
+                if input.key_pressed(hotkeys.next_theme) {
+                    let next = theme.map_or(0, |index| (index + 1) % THEMES.len());
+                    theme = Some(next);
+                    self.settings.palette = THEMES[next].1;
+                    recolored = true;
+                    println!("theme {}", THEMES[next].0);
+                }
+
+                // Speed up and down double and halve the speed, turbo fast-forwards while held
+                if input.key_pressed(hotkeys.speed_up) {
+                    scheduler.set_speed(scheduler.speed() * 2.0);
+                    println!("speed {}x", scheduler.speed());
+                }
+                if input.key_pressed(hotkeys.speed_down) {
+                    scheduler.set_speed(scheduler.speed() / 2.0);
+                    println!("speed {}x", scheduler.speed());
+                }
+                let turbo = input.key_held(hotkeys.turbo);
+
+                // Save states, in the slot picked with the previous and next slot hotkeys
+                if input.key_pressed(hotkeys.previous_slot) {
+                    self.slot = (self.slot + STATE_SLOTS - 1) % STATE_SLOTS;
+                    println!("save state slot {}", self.slot);
+                }
+                if input.key_pressed(hotkeys.next_slot) {
+                    self.slot = (self.slot + 1) % STATE_SLOTS;
+                    println!("save state slot {}", self.slot);
+                }
+                if input.key_pressed(hotkeys.save_state) {
+                    let path = self.state_path(self.slot);
+                    match self.save_state(&path) {
+                        Ok(()) => println!("saved state to {}", path.display()),
+                        Err(e) => eprintln!("could not save state to {}: {}", path.display(), e),
+                    }
+                }
+                if input.key_pressed(hotkeys.load_state) && self.movie.is_some() {
+                    eprintln!("save states cannot be loaded while a movie is recorded or replayed");
+                } else if input.key_pressed(hotkeys.load_state) {
+                    let path = self.state_path(self.slot);
+                    match self.load_state(&path) {
+                        Ok(()) => {
+                            halted = false;
+                            budget = 0;
+                            if let Some((debugger, _)) = &debugger {
+                                debugger.attach(&mut self.mem);
+                            }
+                            println!("loaded state from {}", path.display());
+                        }
+                        Err(e) => eprintln!("could not load state from {}: {}", path.display(), e),
+                    }
+                }
+
                 // Resize the window
                 if let Some(size) = input.window_resized() {
                     pixels.resize_surface(size.width, size.height);
                 }
 
-                // Update internal state and request a redraw
-                // self.cpu.cycle(&mut self.mem);
-                window.request_redraw();
+                // Run the 60 Hz frames that are due. Time passes while paused or
+                // halted too, so it is not caught up on afterwards.
+                let stopped = paused || debugger.as_ref().is_some_and(|(debugger, _)| debugger.is_paused());
+                let frames = scheduler.frames() * if turbo { TURBO_FRAMES } else { 1 };
+                for _ in 0..frames {
+                    if rewinding {
+                        if let Some((cpu, mem)) = rewind.pop() {
+                            self.cpu = cpu;
+                            self.mem = mem;
+                            halted = false;
+                            budget = 0;
+                            if let Some((debugger, _)) = &debugger {
+                                debugger.attach(&mut self.mem);
+                            }
+                        }
+                        display.vblank(&self.cpu);
+                        audio.frame(&Tone::default());
+                        continue;
+                    }
+                    // The beeper is silent while nothing runs
+                    if halted || stopped {
+                        audio.frame(&Tone::default());
+                        continue;
+                    }
+                    // A frame interrupted by the debugger goes on with the keys it started with
+                    if budget == 0 {
+                        rewind.push(&self.cpu, &self.mem);
+
+                        let mut keys = if focused {
+                            self.settings.keymap.state(&input)
+                        } else {
+                            [false; 16]
+                        };
+                        if let Some(movie) = &mut self.movie {
+                            let replaying = !movie.finished();
+                            keys = movie.keys(keys);
+                            if replaying && movie.finished() {
+                                println!("replay finished, the keyboard takes over");
+                            }
+                        }
+                        self.cpu.set_keys(keys);
+                        budget = self.settings.instructions_per_frame;
+                    }
+                    let mut interrupted = false;
+                    while budget > 0 {
+                        if let Some((debugger, _)) = &mut debugger {
+                            if debugger.before(&self.cpu, &self.mem) {
+                                interrupted = true;
+                                break;
+                            }
+                        }
+                        if let Err(e) = self.cpu.cycle(&mut self.mem) {
+                            self.report_fault(&e);
+                            halted = true;
+                            if let Some((debugger, _)) = &mut debugger {
+                                debugger.pause("faulted", &self.cpu, &self.mem);
+                            }
+                            interrupted = true;
+                            break;
+                        }
+                        budget -= 1;
+                        if let Some((debugger, _)) = &mut debugger {
+                            if debugger.after(&self.cpu, &self.mem) {
+                                interrupted = true;
+                                break;
+                            }
+                        }
+                    }
+                    // The timers only tick once the whole frame has run
+                    if budget > 0 {
+                        break;
+                    }
+                    display.vblank(&self.cpu);
+                    audio.frame(&Tone::of(&self.cpu));
+                    self.cpu.timer();
+                    if interrupted {
+                        break;
+                    }
+                }
+
+                // 00FD exits the interpreter
+                if self.cpu.halted() {
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+
+                if frames > 0 || recolored {
+                    window.request_redraw();
+                }
+                *control_flow = ControlFlow::WaitUntil(scheduler.deadline());
             }
         });
     }
 
-    pub fn load_font(&mut self) -> () {
-        let font: [u8; 80] =
-            [0xF0,
-                0x90,
-                0x90,
-                0x90,
-                0xF0,
-                0x20,
-                0x60,
-                0x20,
-                0x20,
-                0x70,
-                0xF0,
-                0x10,
-                0xF0,
-                0x80,
-                0xF0,
-                0xF0,
-                0x10,
-                0xF0,
-                0x10,
-                0xF0,
-                0x90,
-                0x90,
-                0xF0,
-                0x10,
-                0x10,
-                0xF0,
-                0x80,
-                0xF0,
-                0x10,
-                0xF0,
-                0xF0,
-                0x80,
-                0xF0,
-                0x90,
-                0xF0,
-                0xF0,
-                0x10,
-                0x20,
-                0x40,
-                0x40,
-                0xF0,
-                0x90,
-                0xF0,
-                0x90,
-                0xF0,
-                0xF0,
-                0x90,
-                0xF0,
-                0x10,
-                0xF0,
-                0xF0,
-                0x90,
-                0xF0,
-                0x90,
-                0x90,
-                0xE0,
-                0x90,
-                0xE0,
-                0x90,
-                0xE0,
-                0xF0,
-                0x80,
-                0x80,
-                0x80,
-                0xF0,
-                0xE0,
-                0x90,
-                0x90,
-                0x90,
-                0xE0,
-                0xF0,
-                0x80,
-                0xF0,
-                0x80,
-                0xF0,
-                0xF0,
-                0x80,
-                0xF0,
-                0x80,
-                0x80];
-
-        for i in 0..font.len() {
-            self.mem.set(i, font[i]);
+    // Run without a window, audio device or keyboard, with the sound going to
+    // `audio`. With a cycle budget the program runs as fast as possible on a
+    // virtual 60 Hz clock, otherwise in real time. Either way the run ends when
+    // the program exits or jumps to itself.
+    pub fn start_headless(&mut self, cycles: Option<u64>, audio: &mut dyn AudioSink) -> Result<Stop, Chip8Error> {
+        let result = match cycles {
+            Some(cycles) => {
+                let movie = &mut self.movie;
+                let mut keys = || movie.as_mut().map_or([false; 16], |movie| movie.keys([false; 16]));
+                let ipf = self.settings.instructions_per_frame;
+                headless::run(&mut self.cpu, &mut self.mem, cycles, ipf, &mut keys, audio)
+            }
+            None => self.run_realtime(audio),
+        };
+        if let Err(e) = &result {
+            self.report_fault(e);
         }
+        result
     }
 
+    fn run_realtime(&mut self, audio: &mut dyn AudioSink) -> Result<Stop, Chip8Error> {
+        let mut scheduler = Scheduler::with_speed(self.settings.speed);
+        loop {
+            for _ in 0..scheduler.frames() {
+                if let Some(movie) = &mut self.movie {
+                    self.cpu.set_keys(movie.keys([false; 16]));
+                }
+                for _ in 0..self.settings.instructions_per_frame {
+                    if let Some(stop) = headless::stopped(&self.cpu, &self.mem) {
+                        headless::finish_sound(&mut self.cpu, audio);
+                        return Ok(stop);
+                    }
+                    self.cpu.cycle(&mut self.mem)?;
+                }
+                audio.frame(&Tone::of(&self.cpu));
+                self.cpu.timer();
+            }
+            if let Some(remaining) = scheduler.deadline().checked_duration_since(time::Instant::now()) {
+                thread::sleep(remaining);
+            }
+        }
+    }
+
+    // Write the framebuffer as PNG, PBM or ASCII art depending on the extension
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        dump::write(path, &self.cpu, &self.settings.palette)
+    }
+
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        savestate::save_to_file(path, &self.cpu, &self.mem)
+    }
+
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let (cpu, mem) = savestate::load_from_file(path)?;
+        self.cpu = cpu;
+        self.mem = mem;
+        Ok(())
+    }
+
+    // Slots are stored next to the ROM as <rom>.state<slot>
+    fn state_path(&self, slot: u8) -> PathBuf {
+        let rom = self.rom_path.clone().unwrap_or_else(|| PathBuf::from("chip8"));
+        rom.with_extension(format!("state{}", slot))
+    }
+
+    // Print the fault together with the machine state it happened in
+    fn report_fault(&self, error: &Chip8Error) {
+        let pc = self.cpu.pc();
+        match self.mem.read16(pc as usize) {
+            Ok(opcode) => eprintln!("halted: {} (PC {:03X}, opcode {:04X})", error, pc, opcode),
+            Err(_) => eprintln!("halted: {} (PC {:03X})", error, pc),
+        }
+    }
+
+    pub fn load_font(&mut self) {
+        self.mem.load_font();
+    }
+}
+
+impl Default for CHIP8 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
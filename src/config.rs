@@ -0,0 +1,314 @@
+use crate::audio::{AudioSettings, Waveform, WAVEFORMS};
+use crate::chip8::Settings;
+use crate::keypad::{self, Hotkeys, Keymap};
+use crate::palette;
+use crate::persistence::Persistence;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+use winit::event::VirtualKeyCode;
+
+// Settings read from a TOML file. Every section is optional.
+//
+//   [keypad]                   host key for each CHIP-8 key 0-F, by winit key name
+//   1 = "1"
+//   C = "4"
+//
+//   [hotkeys]                  quit, pause, reset, save_state, load_state, previous_slot,
+//   pause = "Space"            next_slot, speed_up, speed_down, turbo, rewind and next_theme
+//
+//   [display]
+//   theme = "amber"            classic, green, amber, lcd or octo
+//   palette = ["000000", "FFFFFF", "AAAAAA", "555555"]
+//                              background, plane 1, plane 2 and both planes;
+//                              fewer colors replace only the first ones
+//   persistence = "blend:3"    off, phosphor, blend or vblank, to hide sprite flicker;
+//                              phosphor:DECAY fades by 0-1 a frame, blend:N averages N frames
+//
+//   [audio]
+//   frequency = 440            pitch of the beep in Hz
+//   waveform = "square"        square, triangle, sawtooth or sine
+//   volume = 0.3               from 0 to 1
+//   attack_ms = 5              fade in and out, to avoid clicks
+//   release_ms = 20
+//
+//   [roms."pong.ch8".keypad]   overrides for the ROM with this file name
+//   1 = "Up"
+//   4 = "Down"
+
+// A config file that cannot be used. The message starts with the section the
+// problem is in, like "[audio] volume should be ...", when there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+pub struct Config {
+    table: Table,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let value: Value = text.parse().map_err(|e: toml::de::Error| ConfigError::new(e.to_string()))?;
+        match value {
+            Value::Table(table) => Ok(Self { table }),
+            _ => Err(ConfigError::new("expected a table at the top level".to_string())),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Config::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // $XDG_CONFIG_HOME/chip8/config.toml, falling back to ~/.config
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("chip8").join("config.toml"))
+    }
+
+    // Apply the global sections and then the overrides for `rom`, and check
+    // that the resulting bindings do not conflict
+    pub fn apply(&self, settings: &mut Settings, rom: &Path) -> Result<(), ConfigError> {
+        apply_sections(&self.table, settings, "")?;
+
+        if let Some(roms) = self.table.get("roms") {
+            let roms = table(roms, "roms")?;
+            if let Some(name) = rom.file_name().map(|name| name.to_string_lossy()) {
+                if let Some(overrides) = roms.get(name.as_ref()) {
+                    let section = format!("roms.\"{}\"", name);
+                    apply_sections(table(overrides, &section)?, settings, &format!("{}.", section))?;
+                }
+            }
+        }
+
+        keypad::check_conflicts(&settings.keymap, &settings.hotkeys).map_err(ConfigError::new)
+    }
+}
+
+fn apply_sections(sections: &Table, settings: &mut Settings, context: &str) -> Result<(), ConfigError> {
+    for (name, value) in sections {
+        let section = format!("{}{}", context, name);
+        match name.as_str() {
+            "keypad" => apply_keypad(table(value, &section)?, &mut settings.keymap, &section)?,
+            "hotkeys" => apply_hotkeys(table(value, &section)?, &mut settings.hotkeys, &section)?,
+            "audio" => apply_audio(table(value, &section)?, &mut settings.audio, &section)?,
+            "display" => apply_display(table(value, &section)?, settings, &section)?,
+            // Only allowed at the top level, and handled by the caller
+            "roms" if context.is_empty() => {}
+            _ => return Err(ConfigError::new(format!("unknown section [{}]", section))),
+        }
+    }
+    Ok(())
+}
+
+fn apply_keypad(section: &Table, keymap: &mut Keymap, context: &str) -> Result<(), ConfigError> {
+    for (name, value) in section {
+        let chip8_key = match u8::from_str_radix(name, 16) {
+            Ok(n) if name.len() == 1 => n,
+            _ => return Err(ConfigError::new(format!("[{}] '{}' is not a CHIP-8 key, expected 0-F", context, name))),
+        };
+        keymap.set_key(chip8_key, key(value, context, name)?);
+    }
+    Ok(())
+}
+
+fn apply_hotkeys(section: &Table, hotkeys: &mut Hotkeys, context: &str) -> Result<(), ConfigError> {
+    for (name, value) in section {
+        let key = key(value, context, name)?;
+        if !hotkeys.set(name, key) {
+            return Err(ConfigError::new(format!("[{}] unknown hotkey '{}'", context, name)));
+        }
+    }
+    Ok(())
+}
+
+fn apply_audio(section: &Table, audio: &mut AudioSettings, context: &str) -> Result<(), ConfigError> {
+    for (name, value) in section {
+        let invalid = |expected: &str| ConfigError::new(format!("[{}] {} should be {}", context, name, expected));
+        match name.as_str() {
+            "frequency" => match number(value) {
+                Some(hz) if hz > 0.0 => audio.frequency = hz as f32,
+                _ => return Err(invalid("a frequency in Hz above 0")),
+            },
+            "waveform" => match value.as_str().and_then(Waveform::from_name) {
+                Some(waveform) => audio.waveform = waveform,
+                None => return Err(invalid(&format!("one of {}", WAVEFORMS.join(", ")))),
+            },
+            "volume" => match number(value) {
+                Some(volume) if (0.0..=1.0).contains(&volume) => audio.volume = volume as f32,
+                _ => return Err(invalid("a number from 0 to 1")),
+            },
+            "attack_ms" | "release_ms" => match number(value) {
+                Some(ms) if ms >= 0.0 => {
+                    let seconds = (ms / 1000.0) as f32;
+                    if name == "attack_ms" {
+                        audio.attack = seconds;
+                    } else {
+                        audio.release = seconds;
+                    }
+                }
+                _ => return Err(invalid("a number of milliseconds")),
+            },
+            _ => return Err(ConfigError::new(format!("[{}] unknown setting '{}'", context, name))),
+        }
+    }
+    Ok(())
+}
+
+fn apply_display(section: &Table, settings: &mut Settings, context: &str) -> Result<(), ConfigError> {
+    // A palette adjusts the theme, whichever order they are written in
+    if let Some(value) = section.get("theme") {
+        settings.palette = value.as_str()
+            .and_then(palette::theme)
+            .ok_or_else(|| ConfigError::new(format!(
+                "[{}] theme should be one of {}", context, palette::theme_names().join(", "))))?;
+    }
+    if let Some(value) = section.get("palette") {
+        let colors = value.as_array()
+            .ok_or_else(|| ConfigError::new(format!("[{}] palette should be a list of RRGGBB colors", context)))?
+            .iter()
+            .map(|color| match color.as_str() {
+                Some(color) => palette::parse_color(color),
+                None => Err("palette colors should be strings".to_string()),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|colors| palette::set_colors(&mut settings.palette, &colors));
+        colors.map_err(|e| ConfigError::new(format!("[{}] {}", context, e)))?;
+    }
+    if let Some(value) = section.get("persistence") {
+        let persistence = value.as_str()
+            .ok_or_else(|| "persistence should be a string".to_string())
+            .and_then(Persistence::parse);
+        settings.persistence = persistence.map_err(|e| ConfigError::new(format!("[{}] {}", context, e)))?;
+    }
+    if let Some(name) = section.keys().find(|name| !["theme", "palette", "persistence"].contains(&name.as_str())) {
+        return Err(ConfigError::new(format!("[{}] unknown setting '{}'", context, name)));
+    }
+    Ok(())
+}
+
+// Integers are accepted wherever a number is
+fn number(value: &Value) -> Option<f64> {
+    value.as_float().or_else(|| value.as_integer().map(|n| n as f64))
+}
+
+fn table<'a>(value: &'a Value, context: &str) -> Result<&'a Table, ConfigError> {
+    value.as_table().ok_or_else(|| ConfigError::new(format!("'{}' should be a section", context)))
+}
+
+fn key(value: &Value, context: &str, name: &str) -> Result<VirtualKeyCode, ConfigError> {
+    let key_name = value.as_str()
+        .ok_or_else(|| ConfigError::new(format!("[{}] {} should be a key name in quotes", context, name)))?;
+    keypad::key_from_name(key_name)
+        .ok_or_else(|| ConfigError::new(format!("[{}] {}: unknown key '{}'", context, name, key_name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(text: &str, rom: &str) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        Config::parse(text)
+            .and_then(|config| config.apply(&mut settings, Path::new(rom)))
+            .map(|_| settings)
+            .map_err(|e| e.message)
+    }
+
+    // The message for a config that should not apply
+    fn error(text: &str) -> String {
+        apply(text, "a.ch8").err().unwrap_or_default()
+    }
+
+    const OVERRIDES: &str = r#"
+        [keypad]
+        1 = "Up"
+        [display]
+        persistence = "blend:2"
+        [roms."pong.ch8".keypad]
+        1 = "Left"
+        [roms."pong.ch8".display]
+        persistence = "off"
+    "#;
+
+    #[test]
+    fn rom_overrides_win_over_global_sections() {
+        let settings = apply(OVERRIDES, "roms/pong.ch8").unwrap();
+        assert_eq!(settings.keymap.key(1), VirtualKeyCode::Left);
+        assert_eq!(settings.persistence, Persistence::Off);
+        // Other ROMs only get the global sections
+        let settings = apply(OVERRIDES, "roms/tetris.ch8").unwrap();
+        assert_eq!(settings.keymap.key(1), VirtualKeyCode::Up);
+        assert_eq!(settings.persistence, Persistence::Blend { frames: 2 });
+    }
+
+    #[test]
+    fn settings_are_applied() {
+        let text = r#"
+            [hotkeys]
+            pause = "Space"
+            [audio]
+            frequency = 220
+            volume = 0.5
+            attack_ms = 10
+        "#;
+        let settings = apply(text, "a.ch8").unwrap();
+        assert_eq!(settings.hotkeys.pause, VirtualKeyCode::Space);
+        assert_eq!(settings.audio.frequency, 220.0);
+        assert_eq!(settings.audio.volume, 0.5);
+        assert_eq!(settings.audio.attack, 0.01);
+    }
+
+    #[test]
+    fn unknown_names_are_rejected() {
+        assert_eq!(error("[video]\nscale = 2"), "unknown section [video]");
+        assert_eq!(
+            error("[roms.\"a.ch8\".roms]"),
+            "unknown section [roms.\"a.ch8\".roms]"
+        );
+        assert_eq!(error("[audio]\nloudness = 1"), "[audio] unknown setting 'loudness'");
+        assert_eq!(error("[display]\nsize = 1"), "[display] unknown setting 'size'");
+        assert_eq!(error("[hotkeys]\nfly = \"F\""), "[hotkeys] unknown hotkey 'fly'");
+        assert_eq!(error("[keypad]\nG = \"F\""), "[keypad] 'G' is not a CHIP-8 key, expected 0-F");
+        assert_eq!(error("[keypad]\n1 = \"Nope\""), "[keypad] 1: unknown key 'Nope'");
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert_eq!(error("[audio]\nvolume = 2"), "[audio] volume should be a number from 0 to 1");
+        assert!(error("[display]\npersistence = \"blend:0\"").starts_with("[display] blend frames"));
+        assert!(apply("keypad = 1", "a.ch8").is_err());
+    }
+
+    #[test]
+    fn conflicting_bindings_are_rejected() {
+        assert_eq!(
+            error("[keypad]\n1 = \"P\""),
+            "P is bound to both keypad 1 and hotkey pause"
+        );
+        // A conflict only in another ROM's overrides does not matter
+        assert!(apply("[roms.\"b.ch8\".keypad]\n1 = \"P\"", "a.ch8").is_ok());
+    }
+}
@@ -1,14 +1,18 @@
-use cpal::Stream;
-use cpal::traits::StreamTrait;
 use crate::op::Op;
-use crate::memory::Memory;
-use winit::event_loop::{EventLoop, ControlFlow};
-use rand::Rng;
-use winit::event::Event;
-use device_query::{DeviceQuery, DeviceState, Keycode};
-use winit::platform::unix::x11::Window;
-use crate::audio;
+use crate::memory::{Memory, BIG_FONT_START};
+use crate::error::Chip8Error;
+use crate::quirks::Quirks;
+use crate::rng::Random;
+use crate::savestate::{self, StateReader, StateWriter};
+use std::io;
 
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+// SUPER-CHIP high resolution mode
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+#[derive(Clone)]
 pub struct Cpu {
     stack: [u16; 16],
     v: [u8; 16],
@@ -18,19 +22,55 @@ pub struct Cpu {
     pc: u16,
     // the program counter
     sp: usize,
-    // the stack pointer
+    // the stack pointer, the number of return addresses on the stack
     dt: u8,
     // delay timer
     st: u8,
     // sound timer
-    framebuffer: [bool; 2048],
-    seed: rand::rngs::ThreadRng,
-    device_state: DeviceState,
-    stream: Stream,
+    framebuffer: Vec<u8>,
+    // one bit per XO-CHIP bitplane for every pixel
+    plane: u8,
+    // the bitplanes selected by FN01
+    hires: bool,
+    // SUPER-CHIP 128x64 mode
+    rpl: [u8; 16],
+    // SUPER-CHIP user flags
+    halted: bool,
+    // set by 00FD
+    audio_pattern: Option<[u8; 16]>,
+    // XO-CHIP audio pattern buffer, None until F002 loads one
+    pitch: u8,
+    // XO-CHIP audio pattern playback pitch
+    keys: [bool; 16],
+    // keypad state, set by the frontend before each cycle
+    rng: Random,
+    // source of CXKK random numbers
+    quirks: Quirks,
+    waiting_for_vblank: bool,
+    // set after a draw when the display wait quirk is enabled
+    key_wait: Option<KeyWait>,
+    // FX0A in progress
+    cleared: bool,
+    // the display was cleared and nothing has been drawn since; only used for
+    // presenting frames, so it is not part of save states
+}
+
+// Progress of an FX0A instruction, which holds the PC until it completes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyWait {
+    // Waiting for a key to go down. Keys already held, as a bitmask, only
+    // count once they have been let go of and pressed again.
+    Press { register: usize, held: u16 },
+    // The key went down, and the key release quirk waits for it to come up
+    Release { register: usize, key: u8 },
 }
 
 impl Cpu {
     pub fn new() -> Self {
+        Cpu::with_quirks(Quirks::default())
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
         Self {
             stack: [0; 16], // the stack
             v: [0; 16], // the V registers
@@ -39,37 +79,54 @@ impl Cpu {
             sp: 0,      // the stack pointer
             dt: 0,      // delay timer
             st: 0,      // sound timer
-            framebuffer: [false; 2048],
-            seed: rand::thread_rng(),
-            device_state: DeviceState::new(),
-            stream: audio::Opt::new().beep(),
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            plane: 1,
+            hires: false,
+            rpl: [0; 16],
+            halted: false,
+            audio_pattern: None,
+            pitch: 64,
+            keys: [false; 16],
+            rng: Random::default(),
+            quirks,
+            waiting_for_vblank: false,
+            key_wait: None,
+            cleared: false,
         }
     }
 
-    pub fn cycle(&mut self, memory: &mut Memory) {
-        // Read the 2 byte opcode at PC
-        let opcode = memory.read16(self.pc as usize);
-
-        assert_eq!(self.pc % 2, 0);
-
-        // Increment the program counter
-        self.pc += 2;
+    // Execute one instruction. On a fault the PC is left on the faulting instruction.
+    pub fn cycle(&mut self, memory: &mut Memory) -> Result<(), Chip8Error> {
+        let address = self.pc;
+        let result = self.execute(memory);
+        if result.is_err() {
+            self.pc = address;
+        }
+        result
+    }
 
-        if self.st == 0 {
-            self.stream.pause();
-        } else {
-            self.stream.play();
+    fn execute(&mut self, memory: &mut Memory) -> Result<(), Chip8Error> {
+        // Nothing runs between a draw and the next vertical blank, or after 00FD
+        if self.waiting_for_vblank || self.halted {
+            return Ok(());
         }
+        // Timers and the display keep going while FX0A waits for the keypad
+        if let Some(wait) = self.key_wait {
+            self.wait_for_key(wait);
+            return Ok(());
+        }
+
+        let address = self.pc;
 
-        // Get current pressed keys
-        let keys: Vec<u8> = self.device_state.get_keys()
-            .iter()
-            .map(|key| Cpu::keycode_to_u8(key))
-            .collect();
+        // Read the 2 byte opcode at PC
+        let opcode = memory.read16(address as usize)?;
 
+        // Increment the program counter
+        self.pc = self.pc.wrapping_add(2);
 
         // Decode the instruction
-        let op = Op::decode(opcode);
+        let op = Op::decode(opcode)
+            .ok_or(Chip8Error::InvalidOpcode { address, opcode })?;
 
         // Parameters pulled out for readability
         let x = Op::x(opcode);
@@ -82,95 +139,124 @@ impl Cpu {
         match op {
             Op::CLR => self.clear_screen(),
             Op::RET => {
+                if self.sp == 0 {
+                    return Err(Chip8Error::StackUnderflow { address });
+                }
+                self.sp -= 1;
                 self.pc = self.stack[self.sp];
-                self.sp = self.sp - 1
             }
             Op::JP => self.pc = nnn,
             Op::CALL => {
-                self.sp += 1;
+                if self.sp == self.stack.len() {
+                    return Err(Chip8Error::StackOverflow { address });
+                }
                 self.stack[self.sp] = self.pc;
+                self.sp += 1;
                 self.pc = nnn;
             }
             Op::SE => {
                 if self.v[x] == kk {
-                    self.pc += 2;
+                    self.skip(memory);
                 }
             }
             Op::SNE => {
                 if self.v[x] != kk {
-                    self.pc += 2;
+                    self.skip(memory);
                 }
             }
             Op::SER => {
                 if self.v[x] == self.v[y] {
-                    self.pc += 2;
+                    self.skip(memory);
                 }
             }
             Op::LD => self.v[x] = kk,
             Op::ADD => self.v[x] = self.v[x].wrapping_add(kk),
-            Op::LDR => self.v[x] = self.v[y].clone(),
-            Op::OR => self.v[x] = self.v[x] | self.v[y],
-            Op::AND => self.v[x] = self.v[x] & self.v[y],
-            Op::XOR => self.v[x] = self.v[x] ^ self.v[y],
+            Op::LDR => self.v[x] = self.v[y],
+            Op::OR => {
+                self.v[x] |= self.v[y];
+                self.reset_vf();
+            }
+            Op::AND => {
+                self.v[x] &= self.v[y];
+                self.reset_vf();
+            }
+            Op::XOR => {
+                self.v[x] ^= self.v[y];
+                self.reset_vf();
+            }
             Op::ADDR => {
-                let val = (self.v[x] as u16 + self.v[y] as u16) as u8;
-                self.v[0xF] = u8::from((self.v[x] as u16 + self.v[y] as u16) > 255);
+                let (val, carry) = self.v[x].overflowing_add(self.v[y]);
                 self.v[x] = val;
+                self.v[0xF] = u8::from(carry);
             }
             Op::SUB => {
-                let val = self.v[x].wrapping_sub(self.v[y]);
-                self.v[0xF] = u8::from(self.v[x] > self.v[y]);
+                let (val, borrow) = self.v[x].overflowing_sub(self.v[y]);
                 self.v[x] = val;
+                self.v[0xF] = u8::from(!borrow);
             }
             Op::SHR => {
-                let val = self.v[x] >> 1;
-                self.v[0xF] = u8::from(self.v[x] % 2 != 0);
-                self.v[x] = val;
+                let source = self.shift_source(x, y);
+                self.v[x] = source >> 1;
+                self.v[0xF] = source & 1;
             }
             Op::SUBN => {
-                let val = self.v[y].wrapping_sub(self.v[x]);
-                self.v[0xF] = u8::from(self.v[y] > self.v[x]);
+                let (val, borrow) = self.v[y].overflowing_sub(self.v[x]);
                 self.v[x] = val;
+                self.v[0xF] = u8::from(!borrow);
             }
             Op::SHL => {
-                let val = self.v[x].wrapping_mul(2);
-                self.v[0xF] = u8::from(self.v[x] & 0b10000000 != 0);
-                self.v[x] = val;
+                let source = self.shift_source(x, y);
+                self.v[x] = source << 1;
+                self.v[0xF] = source >> 7;
             }
             Op::SNER => {
                 if self.v[x] != self.v[y] {
-                    self.pc += 2;
+                    self.skip(memory);
                 }
             }
             Op::LDI => self.i = nnn,
-            Op::JPA => self.pc = nnn + self.v[0] as u16,
+            Op::JPA => {
+                let offset = if self.quirks.jump { self.v[x] } else { self.v[0] };
+                self.pc = nnn + offset as u16;
+            }
             Op::RND => {
-                let rnd: u8 = self.seed.gen();
+                let rnd = self.rng.next_u8(memory);
                 self.v[x] = rnd & kk
             }
 
             Op::DRW => {
-                let sprite = self.read_sprite(self.i, nibble, memory);
-                self.draw_sprite(sprite, self.v[x], self.v[y]);
+                // A height of 0 draws a 16x16 sprite in hires mode and on XO-CHIP. The
+                // original interpreters drew nothing, and so does lores mode here.
+                let large = nibble == 0 && (self.hires || self.quirks.extended_memory);
+                let (width, length) = if large { (16, 32) } else { (8, nibble) };
+                // Each selected plane takes the next sprite in memory
+                let mut address = self.i;
+                let mut collision = false;
+                for plane in [1, 2] {
+                    if self.plane & plane != 0 {
+                        let sprite = self.read_sprite(address, length, memory)?;
+                        collision |= self.draw_sprite(&sprite, width, self.v[x], self.v[y], plane);
+                        address = address.wrapping_add(length as u16);
+                    }
+                }
+                self.v[0xF] = u8::from(collision);
+                self.waiting_for_vblank = self.quirks.display_wait;
+                self.cleared = false;
             }
             Op::SKP => {
-                if keys.contains(&(self.v[x] as u8)) {
-                    self.pc += 2
+                if self.is_key_pressed(self.v[x]) {
+                    self.skip(memory);
                 }
             }
             Op::SKNP => {
-                if !keys.contains(&(self.v[x] as u8)) {
-                    self.pc += 2
+                if !self.is_key_pressed(self.v[x]) {
+                    self.skip(memory);
                 }
             }
             Op::LDD => self.v[x] = self.dt,
             Op::LDK => {
-                let mut presses = self.device_state.get_keys();
-                while presses.is_empty() {
-                    presses = self.device_state.get_keys();
-                }
-                let keycode: Vec<u8> = presses.iter().map(|key| Cpu::keycode_to_u8(key)).collect();
-                self.v[x] = keycode.first().get_or_insert(&(20 as u8)).clone();
+                self.pc = address;
+                self.key_wait = Some(KeyWait::Press { register: x, held: self.key_mask() });
             }
             Op::LDDT => {
                 self.dt = self.v[x];
@@ -182,19 +268,25 @@ impl Cpu {
                 self.i = self.i.wrapping_add(self.v[x] as u16);
             }
             Op::LDF => {
-                self.i = self.v[x].wrapping_mul(5) as u16;
+                self.i = (self.v[x] & 0xF) as u16 * 5;
+            }
+            Op::LDHF => {
+                self.i = (BIG_FONT_START + (self.v[x] as usize & 0xF) * 10) as u16;
             }
             Op::LDB => {
-                memory.set(self.i as usize, self.v[x] / 100);
-                memory.set((self.i + 1) as usize, (self.v[x] % 100) / 10);
-                memory.set((self.i + 2) as usize, self.v[x] % 10)
+                memory.set(self.i as usize, self.v[x] / 100)?;
+                memory.set(self.i as usize + 1, (self.v[x] % 100) / 10)?;
+                memory.set(self.i as usize + 2, self.v[x] % 10)?
             }
             Op::LDII => {
-                for (register_index,v) in self.v.iter().enumerate() {
+                for (register_index, v) in self.v.iter().enumerate() {
                     if register_index > x {
                         break;
                     }
-                    memory.set(self.i as usize + register_index, *v);
+                    memory.set(self.i as usize + register_index, *v)?;
+                }
+                if self.quirks.load_store_increment {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
             }
             Op::LDVX => {
@@ -202,53 +294,156 @@ impl Cpu {
                     if index > x {
                         break;
                     }
-                    *register = memory.read8(index + self.i as usize);
+                    *register = memory.read8(index + self.i as usize)?;
+                }
+                if self.quirks.load_store_increment {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            // The XO-CHIP extensions only exist along with its 64 KiB of memory
+            Op::SCU | Op::SVR | Op::LDRG | Op::LDIL | Op::PLN | Op::AUD | Op::PITCH if !self.quirks.extended_memory => {
+                return Err(Chip8Error::InvalidOpcode { address, opcode })
+            }
+            Op::SCD => self.scroll(0, nibble as isize),
+            Op::SCU => self.scroll(0, -(nibble as isize)),
+            Op::SCR => self.scroll(4, 0),
+            Op::SCL => self.scroll(-4, 0),
+            Op::EXIT => self.halted = true,
+            Op::LOW => self.set_hires(false),
+            Op::HIGH => self.set_hires(true),
+            Op::SRPL => self.rpl[..=x].copy_from_slice(&self.v[..=x]),
+            Op::LRPL => self.v[..=x].copy_from_slice(&self.rpl[..=x]),
+            Op::SVR => {
+                // The range may be given in either order, I is left unchanged
+                for (offset, register) in Cpu::register_range(x, y).into_iter().enumerate() {
+                    memory.set(self.i as usize + offset, self.v[register])?;
                 }
             }
+            Op::LDRG => {
+                for (offset, register) in Cpu::register_range(x, y).into_iter().enumerate() {
+                    self.v[register] = memory.read8(self.i as usize + offset)?;
+                }
+            }
+            Op::LDIL => {
+                self.i = memory.read16(self.pc as usize)?;
+                self.pc = self.pc.wrapping_add(2);
+            }
+            Op::PLN => self.plane = x as u8 & 0b11,
+            Op::AUD => {
+                let mut pattern = [0; 16];
+                for (offset, byte) in pattern.iter_mut().enumerate() {
+                    *byte = memory.read8(self.i as usize + offset)?;
+                }
+                self.audio_pattern = Some(pattern);
+            }
+            Op::PITCH => self.pitch = self.v[x],
         }
+        Ok(())
     }
 
-    pub fn draw (&self, frame: &mut [u8]) {
+    // Write the framebuffer as RGBA. The palette holds the colors for no plane,
+    // plane 1, plane 2 and both planes set.
+    pub fn draw(&self, frame: &mut [u8], palette: &[[u8; 4]; 4]) {
         for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
-            if self.framebuffer[i] {
-                pixel.copy_from_slice(&[0xFF,0xFF,0xFF,0xFF]);
-            } else {
-                pixel.copy_from_slice(&[0x00,0x00,0x00,0xFF]);
-            }
+            pixel.copy_from_slice(&palette[self.framebuffer[i] as usize & 0b11]);
+        }
+    }
+
+    // Skip the next instruction, which is two words long if it is XO-CHIP's F000 NNNN
+    fn skip(&mut self, memory: &Memory) {
+        let next = memory.read16(self.pc as usize).ok();
+        let length = if self.quirks.extended_memory && next == Some(0xF000) { 4 } else { 2 };
+        self.pc = self.pc.wrapping_add(length);
+    }
+
+    fn register_range(x: usize, y: usize) -> Vec<usize> {
+        if x <= y {
+            (x..=y).collect()
+        } else {
+            (y..=x).rev().collect()
+        }
+    }
+
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+    }
+
+    // The register 8XY6/8XYE shift from, depending on the shift quirk
+    fn shift_source(&self, x: usize, y: usize) -> u8 {
+        if self.quirks.shift {
+            self.v[x]
+        } else {
+            self.v[y]
         }
     }
 
-    fn draw_sprite(&mut self, sprite: [u8; 15], x: u8, y: u8) -> bool {
+    // Draw a sprite that is 8 or 16 pixels wide, one or two bytes per row
+    fn draw_sprite(&mut self, sprite: &[u8], width: usize, x: u8, y: u8, plane: u8) -> bool {
+        let (screen_width, screen_height) = (self.width(), self.height());
+        // The starting position always wraps, the rest of the sprite may be clipped
+        let x = x as usize % screen_width;
+        let y = y as usize % screen_height;
         let mut flag = false;
-        for (index, byte) in sprite.iter().enumerate() {
-            for i in 0..8 {
-                if (*byte >> i) & 1 != 0 {
-                    let x_coord: u16 = ((x as u16 + 7 - i) % 64) as u16;
-                    let y_coord: u16 = (((y.wrapping_add(index as u8)) % 32) as u16).wrapping_mul((64 as u16));
-                    let cord = x_coord + y_coord;
-                    for (j, pixel) in self.framebuffer.iter_mut().enumerate() {
-                        if j == cord as usize {
-                            if *pixel {
-                                *pixel = false;
-                                flag = true;
-                            } else {
-                                *pixel = true;
-                            }
-                        }
+        for (index, bytes) in sprite.chunks(width / 8).enumerate() {
+            let row = bytes.iter().fold(0u16, |row, byte| (row << 8) | *byte as u16);
+            for i in 0..width {
+                if (row >> i) & 1 != 0 {
+                    let x_coord = x + width - 1 - i;
+                    let y_coord = y + index;
+                    if self.quirks.clipping && (x_coord >= screen_width || y_coord >= screen_height) {
+                        continue;
+                    }
+                    let x_coord = x_coord % screen_width;
+                    let y_coord = y_coord % screen_height;
+                    let pixel = &mut self.framebuffer[x_coord + y_coord * screen_width];
+                    if *pixel & plane != 0 {
+                        flag = true;
                     }
+                    *pixel ^= plane;
                 }
             }
         }
-        return flag;
+        flag
     }
 
+    // Clearing only affects the selected planes
     fn clear_screen(&mut self) {
-        for (i, pixel) in self.framebuffer.iter_mut().enumerate() {
-            *pixel = false;
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel &= !self.plane;
         }
+        self.cleared = true;
     }
 
-    pub fn timer(&mut self) -> () {
+    // Switching resolution clears the display
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.framebuffer = vec![0; self.width() * self.height()];
+        self.cleared = true;
+    }
+
+    // Move the selected planes by dx, dy pixels, shifting in blank pixels
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let previous = self.framebuffer.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let (source_x, source_y) = (x - dx, y - dy);
+                let source = if (0..width).contains(&source_x) && (0..height).contains(&source_y) {
+                    previous[(source_x + source_y * width) as usize] & self.plane
+                } else {
+                    0
+                };
+                let pixel = &mut self.framebuffer[(x + y * width) as usize];
+                *pixel = (*pixel & !self.plane) | source;
+            }
+        }
+    }
+
+    // Called at 60 Hz, which is also the vertical blank
+    pub fn timer(&mut self) {
+        self.waiting_for_vblank = false;
         if self.st != 0 {
             self.st -= 1;
         }
@@ -257,33 +452,434 @@ impl Cpu {
         }
     }
 
-    pub fn read_sprite(&mut self, i: u16, nibble: usize, memory: &mut Memory) -> [u8; 15] {
-        let mut sprite: [u8; 15] = [0; 15];
-        for index in 0..nibble {
-            sprite[index] = memory.read8((i as usize) + index);
+    pub fn read_sprite(&self, i: u16, length: usize, memory: &Memory) -> Result<Vec<u8>, Chip8Error> {
+        (0..length)
+            .map(|index| memory.read8((i as usize) + index))
+            .collect()
+    }
+
+    // Update the state of the 16 key hexadecimal keypad
+    pub fn set_keys(&mut self, keys: [bool; 16]) {
+        self.keys = keys;
+    }
+
+    fn is_key_pressed(&self, key: u8) -> bool {
+        self.keys.get(key as usize).copied().unwrap_or(false)
+    }
+
+    // Held keys with bit n set for key n
+    fn key_mask(&self) -> u16 {
+        self.keys.iter()
+            .enumerate()
+            .fold(0, |mask, (key, pressed)| mask | (u16::from(*pressed) << key))
+    }
+
+    fn wait_for_key(&mut self, wait: KeyWait) {
+        let keys = self.key_mask();
+        self.key_wait = match wait {
+            KeyWait::Press { register, held } => match (0..16).find(|key| keys & !held & (1 << key) != 0) {
+                Some(key) if self.quirks.key_release => Some(KeyWait::Release { register, key }),
+                Some(key) => {
+                    self.finish_key_wait(register, key);
+                    None
+                }
+                None => Some(KeyWait::Press { register, held: held & keys }),
+            },
+            KeyWait::Release { register, key } if keys & (1 << key) == 0 => {
+                self.finish_key_wait(register, key);
+                None
+            }
+            KeyWait::Release { .. } => Some(wait),
+        };
+    }
+
+    // Store the key and move past the FX0A
+    fn finish_key_wait(&mut self, register: usize, key: u8) {
+        self.v[register] = key;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    // The beeper sounds for as long as the sound timer is non-zero
+    pub fn sound_active(&self) -> bool {
+        self.st != 0
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn rng(&self) -> Random {
+        self.rng
+    }
+
+    pub fn set_rng(&mut self, rng: Random) {
+        self.rng = rng;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    // Return addresses of the active subroutines, innermost last
+    pub fn call_stack(&self) -> &[u16] {
+        &self.stack[..self.sp]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.dt
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.st
+    }
+
+    // Write everything except the keypad, which belongs to the frontend
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.quirks.bits());
+        for register in self.v.iter() {
+            out.u8(*register);
         }
-        return sprite;
-    }
-
-    pub fn keycode_to_u8(key: &Keycode) -> u8 {
-        return match *key {
-            Keycode::Key0 => 0,
-            Keycode::Key1 => 1,
-            Keycode::Key2 => 2,
-            Keycode::Key3 => 3,
-            Keycode::Key4 => 4,
-            Keycode::Key5 => 5,
-            Keycode::Key6 => 6,
-            Keycode::Key7 => 7,
-            Keycode::Key8 => 8,
-            Keycode::Key9 => 9,
-            Keycode::A => 10,
-            Keycode::B => 11,
-            Keycode::C => 12,
-            Keycode::D => 13,
-            Keycode::E => 14,
-            Keycode::F => 15,
-            _ => 20
+        out.u16(self.i);
+        out.u16(self.pc);
+        out.u8(self.sp as u8);
+        for address in self.stack.iter() {
+            out.u16(*address);
+        }
+        out.u8(self.dt);
+        out.u8(self.st);
+        out.bool(self.hires);
+        out.u8(self.plane);
+        out.bytes(&self.framebuffer);
+        out.bytes(&self.rpl);
+        out.bool(self.halted);
+        out.bool(self.waiting_for_vblank);
+        let (tag, register, value) = match self.key_wait {
+            None => (0, 0, 0),
+            Some(KeyWait::Press { register, held }) => (1, register, held),
+            Some(KeyWait::Release { register, key }) => (2, register, key as u16),
         };
+        out.u8(tag);
+        out.u8(register as u8);
+        out.u16(value);
+        out.bool(self.audio_pattern.is_some());
+        out.bytes(&self.audio_pattern.unwrap_or([0; 16]));
+        out.u8(self.pitch);
+        self.rng.save_state(out);
+    }
+
+    pub fn load_state(input: &mut StateReader) -> io::Result<Self> {
+        let mut cpu = Cpu::with_quirks(Quirks::from_bits(input.u8()?));
+        for register in cpu.v.iter_mut() {
+            *register = input.u8()?;
+        }
+        cpu.i = input.u16()?;
+        cpu.pc = input.u16()?;
+        cpu.sp = input.u8()? as usize;
+        if cpu.sp > cpu.stack.len() {
+            return Err(savestate::invalid("stack pointer out of range"));
+        }
+        for address in cpu.stack.iter_mut() {
+            *address = input.u16()?;
+        }
+        cpu.dt = input.u8()?;
+        cpu.st = input.u8()?;
+        cpu.hires = input.bool()?;
+        cpu.plane = input.u8()? & 0b11;
+        cpu.framebuffer = input.bytes()?.to_vec();
+        if cpu.framebuffer.len() != cpu.width() * cpu.height() {
+            return Err(savestate::invalid("framebuffer does not match the resolution"));
+        }
+        cpu.rpl.copy_from_slice(Cpu::expect_length(input.bytes()?, 16)?);
+        cpu.halted = input.bool()?;
+        cpu.waiting_for_vblank = input.bool()?;
+        let (tag, register, value) = (input.u8()?, (input.u8()? & 0xF) as usize, input.u16()?);
+        cpu.key_wait = match tag {
+            0 => None,
+            1 => Some(KeyWait::Press { register, held: value }),
+            2 => Some(KeyWait::Release { register, key: (value & 0xF) as u8 }),
+            _ => return Err(savestate::invalid("unknown key wait state")),
+        };
+        let has_pattern = input.bool()?;
+        let mut pattern = [0; 16];
+        pattern.copy_from_slice(Cpu::expect_length(input.bytes()?, 16)?);
+        cpu.audio_pattern = if has_pattern { Some(pattern) } else { None };
+        cpu.pitch = input.u8()?;
+        cpu.rng = Random::load_state(input)?;
+        Ok(cpu)
+    }
+
+    fn expect_length(bytes: &[u8], length: usize) -> io::Result<&[u8]> {
+        if bytes.len() == length {
+            Ok(bytes)
+        } else {
+            Err(savestate::invalid("field has the wrong length"))
+        }
+    }
+
+    // Each pixel holds one bit per bitplane
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_WIDTH } else { SCREEN_WIDTH }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_HEIGHT } else { SCREEN_HEIGHT }
+    }
+
+    pub fn audio_pattern(&self) -> Option<[u8; 16]> {
+        self.audio_pattern
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    // True between clearing the display and the next sprite being drawn
+    pub fn cleared(&self) -> bool {
+        self.cleared
+    }
+
+    // True once the program has executed 00FD
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CHIP-48 machine with `program` at 0x200 and the font loaded
+    fn load(program: &[u16]) -> (Cpu, Memory) {
+        load_with(Quirks::chip48(), program)
+    }
+
+    fn load_with(quirks: Quirks, program: &[u16]) -> (Cpu, Memory) {
+        let mut memory = Memory::for_quirks(quirks);
+        memory.load_font();
+        let rom: Vec<u8> = program.iter().flat_map(|opcode| opcode.to_be_bytes()).collect();
+        memory.load_rom(&rom).unwrap();
+        (Cpu::with_quirks(quirks), memory)
+    }
+
+    fn step(cpu: &mut Cpu, memory: &mut Memory, cycles: usize) {
+        for _ in 0..cycles {
+            cpu.cycle(memory).unwrap();
+        }
+    }
+
+    // Run one 8XYN instruction with X = F, where the flag has to win over the result
+    fn flag_op(opcode: u16, vf: u8, v1: u8) -> u8 {
+        let (mut cpu, mut memory) = load(&[opcode]);
+        cpu.v[0xF] = vf;
+        cpu.v[1] = v1;
+        step(&mut cpu, &mut memory, 1);
+        cpu.v[0xF]
+    }
+
+    #[test]
+    fn add_into_vf_leaves_the_carry() {
+        assert_eq!(flag_op(0x8F14, 0xFF, 0x01), 1);
+        assert_eq!(flag_op(0x8F14, 0x10, 0x20), 0);
+    }
+
+    #[test]
+    fn sub_into_vf_leaves_the_borrow_flag() {
+        assert_eq!(flag_op(0x8F15, 0x05, 0x03), 1);
+        assert_eq!(flag_op(0x8F15, 0x03, 0x05), 0);
+        assert_eq!(flag_op(0x8F15, 0x03, 0x03), 1);
+    }
+
+    #[test]
+    fn subn_into_vf_leaves_the_borrow_flag() {
+        assert_eq!(flag_op(0x8F17, 0x03, 0x05), 1);
+        assert_eq!(flag_op(0x8F17, 0x05, 0x03), 0);
+        assert_eq!(flag_op(0x8F17, 0x03, 0x03), 1);
+    }
+
+    #[test]
+    fn shifts_into_vf_leave_the_shifted_out_bit() {
+        // CHIP-48 shifts Vx in place, here VF itself
+        assert_eq!(flag_op(0x8F16, 0x03, 0x00), 1);
+        assert_eq!(flag_op(0x8F16, 0x02, 0x00), 0);
+        assert_eq!(flag_op(0x8F1E, 0x80, 0x00), 1);
+        assert_eq!(flag_op(0x8F1E, 0x7F, 0x00), 0);
+    }
+
+    #[test]
+    fn sub_of_equal_values_sets_no_borrow() {
+        let (mut cpu, mut memory) = load(&[0x8015, 0x8017]);
+        cpu.v[0] = 7;
+        cpu.v[1] = 7;
+        step(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0, 1));
+        cpu.v[0] = 7;
+        step(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.v[0], cpu.v[0xF]), (0, 1));
+    }
+
+    #[test]
+    fn memory_ends_at_4k_outside_xo_chip() {
+        // FX55 with I = 0xFFF stores V1 past the end
+        let (mut cpu, mut memory) = load(&[0xAFFF, 0xF155]);
+        step(&mut cpu, &mut memory, 1);
+        assert_eq!(cpu.cycle(&mut memory), Err(Chip8Error::MemoryOutOfBounds { address: 0x1000 }));
+
+        let (mut cpu, mut memory) = load_with(Quirks::xo_chip(), &[0xAFFF, 0xF155]);
+        step(&mut cpu, &mut memory, 2);
+        assert_eq!(memory.size(), 0x10000);
+    }
+
+    #[test]
+    fn long_index_loads_are_xo_chip_only() {
+        let (mut cpu, mut memory) = load(&[0xF000, 0x1234]);
+        assert_eq!(cpu.cycle(&mut memory), Err(Chip8Error::InvalidOpcode { address: 0x200, opcode: 0xF000 }));
+        assert_eq!(cpu.pc(), 0x200);
+
+        let (mut cpu, mut memory) = load_with(Quirks::xo_chip(), &[0xF000, 0x1234]);
+        step(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.i, cpu.pc()), (0x1234, 0x204));
+    }
+
+    #[test]
+    fn xo_chip_opcodes_fault_on_other_profiles() {
+        for &opcode in [0x5012, 0x5013, 0xF101, 0xF002, 0xF03A, 0x00D1].iter() {
+            let (mut cpu, mut memory) = load_with(Quirks::cosmac_vip(), &[opcode]);
+            assert_eq!(cpu.cycle(&mut memory), Err(Chip8Error::InvalidOpcode { address: 0x200, opcode }));
+
+            let (mut cpu, mut memory) = load_with(Quirks::xo_chip(), &[opcode]);
+            assert_eq!(cpu.cycle(&mut memory), Ok(()), "{:04X}", opcode);
+        }
+    }
+
+    // How many pixels DXY0 lights with a solid sprite at 300
+    fn large_sprite_pixels(quirks: Quirks, program: &[u16]) -> usize {
+        let (mut cpu, mut memory) = load_with(quirks, program);
+        for offset in 0..32 {
+            memory.set(0x300 + offset, 0xFF).unwrap();
+        }
+        step(&mut cpu, &mut memory, program.len());
+        cpu.framebuffer().iter().filter(|pixel| **pixel != 0).count()
+    }
+
+    #[test]
+    fn zero_height_sprites_are_16x16_in_hires_and_on_xo_chip() {
+        assert_eq!(large_sprite_pixels(Quirks::cosmac_vip(), &[0xA300, 0xD010]), 0);
+        assert_eq!(large_sprite_pixels(Quirks::chip48(), &[0xA300, 0xD010]), 0);
+        assert_eq!(large_sprite_pixels(Quirks::chip48(), &[0x00FF, 0xA300, 0xD010]), 256);
+        assert_eq!(large_sprite_pixels(Quirks::xo_chip(), &[0xA300, 0xD010]), 256);
+    }
+
+    #[test]
+    fn the_stack_holds_sixteen_calls() {
+        // Each level calls the next one, two bytes further on
+        let program: Vec<u16> = (0..17).map(|level| 0x2202 + 2 * level).collect();
+        let (mut cpu, mut memory) = load(&program);
+        step(&mut cpu, &mut memory, 16);
+        assert_eq!(cpu.sp, 16);
+        assert_eq!((cpu.stack[0], cpu.stack[15]), (0x202, 0x220));
+        assert_eq!(cpu.cycle(&mut memory), Err(Chip8Error::StackOverflow { address: 0x220 }));
+    }
+
+    #[test]
+    fn return_pops_the_innermost_call() {
+        let (mut cpu, mut memory) = load(&[0x2204, 0x1202, 0x00EE]);
+        step(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.pc(), cpu.sp), (0x202, 0));
+        let (mut cpu, mut memory) = load(&[0x00EE]);
+        assert_eq!(cpu.cycle(&mut memory), Err(Chip8Error::StackUnderflow { address: 0x200 }));
+    }
+
+    #[test]
+    fn font_characters_use_the_low_digit() {
+        let (mut cpu, mut memory) = load(&[0x60AB, 0xF029]);
+        step(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.i, 0xB * 5);
+    }
+
+    fn keys(pressed: &[usize]) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for key in pressed {
+            keys[*key] = true;
+        }
+        keys
+    }
+
+    // FX0A into V3, then a loop; returns once the wait has started
+    fn wait_for_key(key_release: bool) -> (Cpu, Memory) {
+        let (mut cpu, mut memory) = load_with(Quirks { key_release, ..Quirks::chip48() }, &[0xF30A, 0x1202]);
+        step(&mut cpu, &mut memory, 1);
+        (cpu, memory)
+    }
+
+    #[test]
+    fn key_wait_completes_on_press() {
+        let (mut cpu, mut memory) = wait_for_key(false);
+        step(&mut cpu, &mut memory, 3);
+        assert_eq!(cpu.pc(), 0x200);
+        cpu.set_keys(keys(&[7]));
+        step(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.pc(), cpu.registers()[3]), (0x202, 7));
+    }
+
+    #[test]
+    fn key_wait_completes_on_release() {
+        let (mut cpu, mut memory) = wait_for_key(true);
+        cpu.set_keys(keys(&[7]));
+        step(&mut cpu, &mut memory, 3);
+        assert_eq!(cpu.pc(), 0x200);
+        cpu.set_keys(keys(&[]));
+        step(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.pc(), cpu.registers()[3]), (0x202, 7));
+    }
+
+    #[test]
+    fn key_held_before_the_wait_does_not_count() {
+        let (mut cpu, mut memory) = load_with(Quirks { key_release: true, ..Quirks::chip48() }, &[0xF30A, 0x1202]);
+        cpu.set_keys(keys(&[5]));
+        step(&mut cpu, &mut memory, 1);
+        // Letting go of the key that was already down does not finish the wait
+        cpu.set_keys(keys(&[]));
+        step(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.pc(), 0x200);
+        // Once released it is a fresh press like any other
+        cpu.set_keys(keys(&[5]));
+        step(&mut cpu, &mut memory, 1);
+        cpu.set_keys(keys(&[]));
+        step(&mut cpu, &mut memory, 1);
+        assert_eq!((cpu.pc(), cpu.registers()[3]), (0x202, 5));
+    }
+
+    #[test]
+    fn timers_run_during_key_wait() {
+        let (mut cpu, mut memory) = load(&[0x6005, 0xF015, 0xF018, 0xF30A]);
+        step(&mut cpu, &mut memory, 4);
+        for _ in 0..3 {
+            step(&mut cpu, &mut memory, 10);
+            cpu.timer();
+        }
+        assert_eq!(cpu.pc(), 0x206);
+        assert_eq!((cpu.delay_timer(), cpu.sound_timer()), (2, 2));
     }
 }
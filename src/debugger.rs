@@ -0,0 +1,607 @@
+use crate::cpu::Cpu;
+use crate::disasm;
+use crate::memory::{Access, Memory, Watchpoint};
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+const HELP: &str = "\
+commands:
+  c, continue              resume execution
+  p, pause                 pause execution
+  s, step [n]              execute n instructions (default 1)
+  n, next                  step over a CALL
+  f, finish                run until the current subroutine returns
+  b, break <addr> [if <cond>]
+  b, break if <cond>       break on a PC and/or a register condition,
+                           e.g. 'b 0x23A if V3 == 0x10'
+  d, delete <id>           remove a breakpoint
+  w, watch <addr> [r|w|rw] break when an address is read and/or written
+  u, unwatch <addr>        remove a watchpoint
+  l, list                  show breakpoints and watchpoints
+  r, regs                  show the registers
+  x <addr> [len]           dump memory
+  h, help                  show this help";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    V(usize),
+    I,
+    Dt,
+    St,
+    Sp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// A register comparison such as V3 == 0x10
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: Option<u16>,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    Pause,
+    Step(u32),
+    StepOver,
+    StepOut,
+    Break(Breakpoint),
+    Delete(usize),
+    Watch(Watchpoint),
+    Unwatch(usize),
+    List,
+    Registers,
+    Examine(usize, usize),
+    Help,
+}
+
+// How execution proceeds until the debugger stops it again
+enum Mode {
+    Run,
+    Step(u32),
+    StepOver { return_to: u16, depth: usize },
+    StepOut { depth: usize },
+}
+
+pub struct Debugger {
+    paused: bool,
+    mode: Mode,
+    // Each breakpoint with whether it held before the last instruction. A
+    // breakpoint only stops execution when it starts to hold, so a condition
+    // that stays true does not stop every instruction after a continue.
+    breakpoints: Vec<Option<(Breakpoint, bool)>>,
+    watchpoints: Vec<Watchpoint>,
+    // breakpoints at this PC are ignored once, so resuming from one works
+    resume_at: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new(paused: bool) -> Self {
+        Self {
+            paused,
+            mode: Mode::Run,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            resume_at: None,
+        }
+    }
+
+    // Read commands from stdin on a separate thread so the window keeps running
+    pub fn spawn_repl() -> Receiver<Command> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            prompt();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if !line.trim().is_empty() {
+                    match Command::parse(&line) {
+                        Ok(command) => {
+                            if sender.send(command).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                prompt();
+            }
+        });
+        receiver
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Stop execution and show where the machine is
+    pub fn pause(&mut self, reason: &str, cpu: &Cpu, memory: &Memory) {
+        self.paused = true;
+        self.mode = Mode::Run;
+        println!("{}", reason);
+        print_location(cpu, memory);
+    }
+
+    // Apply the debugger's watchpoints to memory, e.g. after a state was restored
+    pub fn attach(&self, memory: &mut Memory) {
+        memory.set_watchpoints(self.watchpoints.clone());
+    }
+
+    pub fn handle(&mut self, command: Command, cpu: &Cpu, memory: &mut Memory) {
+        match command {
+            Command::Continue => self.resume(Mode::Run, cpu),
+            Command::Pause => self.pause("paused", cpu, memory),
+            Command::Step(count) => self.resume(Mode::Step(count.max(1)), cpu),
+            Command::StepOver => {
+                let is_call = memory.read16(cpu.pc() as usize)
+                    .map(|opcode| opcode & 0xF000 == 0x2000)
+                    .unwrap_or(false);
+                if is_call {
+                    let mode = Mode::StepOver { return_to: cpu.pc().wrapping_add(2), depth: cpu.sp() };
+                    self.resume(mode, cpu);
+                } else {
+                    self.resume(Mode::Step(1), cpu);
+                }
+            }
+            Command::StepOut => {
+                if cpu.sp() == 0 {
+                    println!("not inside a subroutine");
+                } else {
+                    self.resume(Mode::StepOut { depth: cpu.sp() }, cpu);
+                }
+            }
+            Command::Break(breakpoint) => {
+                self.breakpoints.push(Some((breakpoint, false)));
+                println!("breakpoint {}: {}", self.breakpoints.len() - 1, describe_breakpoint(&breakpoint));
+            }
+            Command::Delete(id) => match self.breakpoints.get_mut(id) {
+                Some(breakpoint @ Some(_)) => {
+                    *breakpoint = None;
+                    println!("deleted breakpoint {}", id);
+                }
+                _ => println!("no breakpoint {}", id),
+            },
+            Command::Watch(watchpoint) => {
+                self.watchpoints.retain(|w| w.address != watchpoint.address);
+                self.watchpoints.push(watchpoint);
+                self.attach(memory);
+                println!("watching {}", describe_watchpoint(&watchpoint));
+            }
+            Command::Unwatch(address) => {
+                let before = self.watchpoints.len();
+                self.watchpoints.retain(|w| w.address != address);
+                if self.watchpoints.len() == before {
+                    println!("no watchpoint at {:03X}", address);
+                } else {
+                    self.attach(memory);
+                    println!("removed watchpoint at {:03X}", address);
+                }
+            }
+            Command::List => {
+                for (id, breakpoint) in self.breakpoints.iter().enumerate() {
+                    if let Some((breakpoint, _)) = breakpoint {
+                        println!("breakpoint {}: {}", id, describe_breakpoint(breakpoint));
+                    }
+                }
+                for watchpoint in self.watchpoints.iter() {
+                    println!("watchpoint: {}", describe_watchpoint(watchpoint));
+                }
+            }
+            Command::Registers => print_registers(cpu),
+            Command::Examine(address, length) => print_memory(memory, address, length),
+            Command::Help => println!("{}", HELP),
+        }
+    }
+
+    fn resume(&mut self, mode: Mode, cpu: &Cpu) {
+        self.mode = mode;
+        self.paused = false;
+        self.resume_at = Some(cpu.pc());
+    }
+
+    // Called before each instruction; true when execution has to stop
+    pub fn before(&mut self, cpu: &Cpu, memory: &Memory) -> bool {
+        if self.paused {
+            return true;
+        }
+        let mut hit = None;
+        for (id, slot) in self.breakpoints.iter_mut().enumerate() {
+            if let Some((breakpoint, held)) = slot {
+                let holds = triggered(breakpoint, cpu);
+                if holds && !*held && hit.is_none() {
+                    hit = Some(id);
+                }
+                *held = holds;
+            }
+        }
+        if self.resume_at.take() == Some(cpu.pc()) {
+            return false;
+        }
+        if let Some(id) = hit {
+            self.pause(&format!("breakpoint {}", id), cpu, memory);
+            return true;
+        }
+        false
+    }
+
+    // Called after each instruction; true when execution has to stop
+    pub fn after(&mut self, cpu: &Cpu, memory: &Memory) -> bool {
+        if let Some(hit) = memory.take_watch_hit() {
+            let access = match hit.access {
+                Access::Read => "read",
+                Access::Write => "write",
+            };
+            let reason = format!("watchpoint: {} {:02X} at {:03X}", access, hit.value, hit.address);
+            self.pause(&reason, cpu, memory);
+            return true;
+        }
+        let done = match &mut self.mode {
+            Mode::Run => false,
+            Mode::Step(remaining) => {
+                *remaining -= 1;
+                *remaining == 0
+            }
+            Mode::StepOver { return_to, depth } => cpu.pc() == *return_to && cpu.sp() == *depth,
+            Mode::StepOut { depth } => cpu.sp() < *depth,
+        };
+        if done {
+            self.pause("stopped", cpu, memory);
+        }
+        done
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, arguments) = match words.split_first() {
+            Some((name, arguments)) => (*name, arguments),
+            None => return Err("empty command".to_string()),
+        };
+        match name {
+            "c" | "continue" => Ok(Command::Continue),
+            "p" | "pause" => Ok(Command::Pause),
+            "s" | "step" => match arguments.first() {
+                Some(count) => Ok(Command::Step(parse_number(count)? as u32)),
+                None => Ok(Command::Step(1)),
+            },
+            "n" | "next" => Ok(Command::StepOver),
+            "f" | "finish" => Ok(Command::StepOut),
+            "b" | "break" => parse_breakpoint(arguments).map(Command::Break),
+            "d" | "delete" => Ok(Command::Delete(parse_number(argument(arguments, 0)?)?)),
+            "w" | "watch" => {
+                let address = parse_number(argument(arguments, 0)?)?;
+                let (read, write) = match arguments.get(1).copied().unwrap_or("rw") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    other => return Err(format!("expected r, w or rw, got '{}'", other)),
+                };
+                Ok(Command::Watch(Watchpoint { address, read, write }))
+            }
+            "u" | "unwatch" => Ok(Command::Unwatch(parse_number(argument(arguments, 0)?)?)),
+            "l" | "list" => Ok(Command::List),
+            "r" | "regs" => Ok(Command::Registers),
+            "x" => {
+                let address = parse_number(argument(arguments, 0)?)?;
+                let length = match arguments.get(1) {
+                    Some(length) => parse_number(length)?,
+                    None => 16,
+                };
+                Ok(Command::Examine(address, length))
+            }
+            "h" | "help" => Ok(Command::Help),
+            other => Err(format!("unknown command '{}', try 'help'", other)),
+        }
+    }
+}
+
+fn argument<'a>(arguments: &[&'a str], index: usize) -> Result<&'a str, String> {
+    arguments.get(index).copied().ok_or_else(|| "missing argument".to_string())
+}
+
+// Numbers are hexadecimal with a 0x prefix, decimal otherwise
+fn parse_number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a number", text))
+}
+
+fn parse_breakpoint(arguments: &[&str]) -> Result<Breakpoint, String> {
+    let (address, rest) = match arguments.first() {
+        Some(&"if") => (None, arguments),
+        Some(address) => (Some(parse_number(address)? as u16), &arguments[1..]),
+        None => return Err("expected an address or a condition".to_string()),
+    };
+    let condition = match rest {
+        [] => None,
+        ["if", register, comparison, value] => Some(Condition {
+            register: parse_register(register)?,
+            comparison: parse_comparison(comparison)?,
+            value: parse_number(value)? as u16,
+        }),
+        _ => return Err("expected a condition like 'if V3 == 0x10'".to_string()),
+    };
+    Ok(Breakpoint { address, condition })
+}
+
+fn parse_register(text: &str) -> Result<Register, String> {
+    let upper = text.to_ascii_uppercase();
+    match upper.as_str() {
+        "I" => Ok(Register::I),
+        "DT" => Ok(Register::Dt),
+        "ST" => Ok(Register::St),
+        "SP" => Ok(Register::Sp),
+        _ => upper.strip_prefix('V')
+            .filter(|index| index.len() == 1)
+            .and_then(|index| usize::from_str_radix(index, 16).ok())
+            .map(Register::V)
+            .ok_or_else(|| format!("unknown register '{}'", text)),
+    }
+}
+
+fn parse_comparison(text: &str) -> Result<Comparison, String> {
+    match text {
+        "==" => Ok(Comparison::Eq),
+        "!=" => Ok(Comparison::Ne),
+        "<" => Ok(Comparison::Lt),
+        "<=" => Ok(Comparison::Le),
+        ">" => Ok(Comparison::Gt),
+        ">=" => Ok(Comparison::Ge),
+        _ => Err(format!("unknown comparison '{}'", text)),
+    }
+}
+
+fn triggered(breakpoint: &Breakpoint, cpu: &Cpu) -> bool {
+    let at_address = breakpoint.address.is_none_or(|address| address == cpu.pc());
+    let condition_holds = breakpoint.condition.is_none_or(|condition| {
+        let value = match condition.register {
+            Register::V(index) => cpu.registers()[index] as u16,
+            Register::I => cpu.i(),
+            Register::Dt => cpu.delay_timer() as u16,
+            Register::St => cpu.sound_timer() as u16,
+            Register::Sp => cpu.sp() as u16,
+        };
+        match condition.comparison {
+            Comparison::Eq => value == condition.value,
+            Comparison::Ne => value != condition.value,
+            Comparison::Lt => value < condition.value,
+            Comparison::Le => value <= condition.value,
+            Comparison::Gt => value > condition.value,
+            Comparison::Ge => value >= condition.value,
+        }
+    });
+    at_address && condition_holds
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    let address = breakpoint.address.map(|address| format!("{:03X}", address));
+    let condition = breakpoint.condition.map(|condition| {
+        let register = match condition.register {
+            Register::V(index) => format!("V{:X}", index),
+            Register::I => "I".to_string(),
+            Register::Dt => "DT".to_string(),
+            Register::St => "ST".to_string(),
+            Register::Sp => "SP".to_string(),
+        };
+        let comparison = match condition.comparison {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        };
+        format!("if {} {} 0x{:X}", register, comparison, condition.value)
+    });
+    match (address, condition) {
+        (Some(address), Some(condition)) => format!("{} {}", address, condition),
+        (Some(address), None) => address,
+        (None, Some(condition)) => condition,
+        (None, None) => "always".to_string(),
+    }
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let access = match (watchpoint.read, watchpoint.write) {
+        (true, true) => "rw",
+        (true, false) => "r",
+        _ => "w",
+    };
+    format!("{:03X} ({})", watchpoint.address, access)
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    let _ = io::stdout().flush();
+}
+
+fn print_location(cpu: &Cpu, memory: &Memory) {
+    match memory.read16(cpu.pc() as usize) {
+        Ok(opcode) => {
+            let next = memory.read16(cpu.pc() as usize + 2).ok();
+            println!("{:03X}: {:04X}  {}", cpu.pc(), opcode, disasm::disassemble(opcode, next));
+        }
+        Err(_) => println!("{:03X}: ????", cpu.pc()),
+    }
+}
+
+fn print_registers(cpu: &Cpu) {
+    println!(
+        "PC {:03X}  I {:03X}  SP {}  DT {:02X}  ST {:02X}",
+        cpu.pc(), cpu.i(), cpu.sp(), cpu.delay_timer(), cpu.sound_timer()
+    );
+    let registers: Vec<String> = cpu.registers().iter()
+        .enumerate()
+        .map(|(index, value)| format!("V{:X} {:02X}", index, value))
+        .collect();
+    println!("{}", registers[..8].join("  "));
+    println!("{}", registers[8..].join("  "));
+    let stack: Vec<String> = cpu.call_stack().iter().map(|address| format!("{:03X}", address)).collect();
+    println!("stack [{}]", stack.join(" "));
+}
+
+fn print_memory(memory: &Memory, address: usize, length: usize) {
+    for row in (address..address + length).step_by(16) {
+        let bytes: Vec<String> = (row..(row + 16).min(address + length))
+            .map(|index| match memory.peek(index) {
+                Some(byte) => format!("{:02X}", byte),
+                None => "--".to_string(),
+            })
+            .collect();
+        println!("{:04X}: {}", row, bytes.join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::WatchHit;
+
+    fn load(program: &[u16]) -> (Cpu, Memory) {
+        let rom: Vec<u8> = program.iter().flat_map(|opcode| opcode.to_be_bytes()).collect();
+        let mut memory = Memory::new();
+        memory.load_rom(&rom).unwrap();
+        (Cpu::new(), memory)
+    }
+
+    // Run the way the frontend does until the debugger stops, and return how
+    // many instructions ran
+    fn run(debugger: &mut Debugger, cpu: &mut Cpu, memory: &mut Memory) -> usize {
+        for count in 0..1000 {
+            if debugger.before(cpu, memory) {
+                return count;
+            }
+            cpu.cycle(memory).unwrap();
+            if debugger.after(cpu, memory) {
+                return count + 1;
+            }
+        }
+        panic!("the debugger did not stop");
+    }
+
+    fn command(debugger: &mut Debugger, line: &str, cpu: &mut Cpu, memory: &mut Memory) -> usize {
+        debugger.handle(Command::parse(line).unwrap(), cpu, memory);
+        run(debugger, cpu, memory)
+    }
+
+    #[test]
+    fn breakpoints_are_parsed() {
+        let condition = Condition { register: Register::V(3), comparison: Comparison::Eq, value: 0x10 };
+        assert_eq!(
+            Command::parse("b 0x23A if V3 == 0x10"),
+            Ok(Command::Break(Breakpoint { address: Some(0x23A), condition: Some(condition) }))
+        );
+        assert_eq!(
+            Command::parse("break 512"),
+            Ok(Command::Break(Breakpoint { address: Some(0x200), condition: None }))
+        );
+        let condition = Condition { register: Register::Sp, comparison: Comparison::Ge, value: 2 };
+        assert_eq!(
+            Command::parse("b if sp >= 2"),
+            Ok(Command::Break(Breakpoint { address: None, condition: Some(condition) }))
+        );
+        assert!(Command::parse("b").is_err());
+        assert!(Command::parse("b 0x200 if V3 ==").is_err());
+        assert!(Command::parse("b if VG == 1").is_err());
+        assert!(Command::parse("b if V1 =< 1").is_err());
+    }
+
+    #[test]
+    fn conditions_stop_only_when_they_start_to_hold() {
+        // V0 is 1 for two instructions of every pass through the loop
+        let (mut cpu, mut memory) = load(&[0x6001, 0x6101, 0x6000, 0x1200]);
+        let mut debugger = Debugger::new(false);
+        debugger.handle(Command::parse("b if V0 == 1").unwrap(), &cpu, &mut memory);
+        assert_eq!(run(&mut debugger, &mut cpu, &mut memory), 1);
+        assert_eq!(cpu.pc(), 0x202);
+        assert_eq!(command(&mut debugger, "c", &mut cpu, &mut memory), 4);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    // Calls 206 from 200, and 206 calls itself until V0 reaches 3
+    const RECURSION: [u16; 7] = [0x2206, 0x1202, 0x0000, 0x7001, 0x3003, 0x2206, 0x00EE];
+
+    #[test]
+    fn step_over_finishes_after_the_matching_return() {
+        let (mut cpu, mut memory) = load(&RECURSION);
+        let mut debugger = Debugger::new(true);
+        command(&mut debugger, "s 3", &mut cpu, &mut memory);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x20A, 1));
+        // The nested calls return to 20C too, but deeper in the stack
+        command(&mut debugger, "n", &mut cpu, &mut memory);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x20C, 1));
+        assert_eq!(cpu.registers()[0], 3);
+    }
+
+    #[test]
+    fn step_over_steps_other_instructions() {
+        let (mut cpu, mut memory) = load(&RECURSION);
+        let mut debugger = Debugger::new(true);
+        command(&mut debugger, "s", &mut cpu, &mut memory);
+        assert_eq!(command(&mut debugger, "n", &mut cpu, &mut memory), 1);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x208, 1));
+    }
+
+    #[test]
+    fn step_out_runs_until_the_subroutine_returns() {
+        let (mut cpu, mut memory) = load(&RECURSION);
+        let mut debugger = Debugger::new(true);
+        command(&mut debugger, "s 3", &mut cpu, &mut memory);
+        command(&mut debugger, "f", &mut cpu, &mut memory);
+        assert_eq!((cpu.pc(), cpu.sp()), (0x202, 0));
+        assert_eq!(cpu.registers()[0], 3);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        // Store V0 at 300, then read it back
+        let (mut cpu, mut memory) = load(&[0xA300, 0x6042, 0xF055, 0xF065, 0x1208]);
+        let mut debugger = Debugger::new(false);
+        debugger.handle(Command::parse("w 0x300").unwrap(), &cpu, &mut memory);
+        assert_eq!(run(&mut debugger, &mut cpu, &mut memory), 3);
+        assert_eq!(cpu.pc(), 0x206);
+        assert_eq!(command(&mut debugger, "c", &mut cpu, &mut memory), 1);
+        assert_eq!(cpu.pc(), 0x208);
+    }
+
+    #[test]
+    fn watchpoints_see_memory_accesses() {
+        let mut memory = Memory::new();
+        memory.set_watchpoints(vec![Watchpoint { address: 0x300, read: false, write: true }]);
+        memory.read8(0x300).unwrap();
+        assert_eq!(memory.take_watch_hit(), None);
+        memory.set(0x300, 7).unwrap();
+        assert_eq!(memory.take_watch_hit(), Some(WatchHit { address: 0x300, access: Access::Write, value: 7 }));
+        assert_eq!(memory.take_watch_hit(), None);
+
+        memory.set_watchpoints(vec![Watchpoint { address: 0x300, read: true, write: false }]);
+        memory.set(0x300, 8).unwrap();
+        assert_eq!(memory.take_watch_hit(), None);
+        assert_eq!(memory.read8(0x300), Ok(8));
+        assert_eq!(memory.take_watch_hit(), Some(WatchHit { address: 0x300, access: Access::Read, value: 8 }));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::op::Op;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+// Render one instruction in Cowgod-style mnemonics, e.g. "LD V3, 0x1F".
+// `next` is the following word, which F000 NNNN takes as its operand.
+pub fn disassemble(opcode: u16, next: Option<u16>) -> String {
+    disassemble_with(opcode, next, &|address| format!("0x{:03X}", address))
+}
+
+// Like disassemble, with addresses rendered by `address` so a listing can show labels
+pub fn disassemble_with(opcode: u16, next: Option<u16>, address: &dyn Fn(u16) -> String) -> String {
+    let op = match Op::decode(opcode) {
+        Some(op) => op,
+        None => return format!("DW 0x{:04X}", opcode),
+    };
+
+    let x = Op::x(opcode);
+    let y = Op::y(opcode);
+    let kk = Op::kk(opcode);
+    let nnn = Op::nnn(opcode);
+    let nibble = Op::nibble(opcode);
+
+    match op {
+        Op::CLR => "CLS".to_string(),
+        Op::RET => "RET".to_string(),
+        Op::JP => format!("JP {}", address(nnn)),
+        Op::CALL => format!("CALL {}", address(nnn)),
+        Op::SE => format!("SE V{:X}, 0x{:02X}", x, kk),
+        Op::SNE => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        Op::SER => format!("SE V{:X}, V{:X}", x, y),
+        Op::LD => format!("LD V{:X}, 0x{:02X}", x, kk),
+        Op::ADD => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        Op::LDR => format!("LD V{:X}, V{:X}", x, y),
+        Op::OR => format!("OR V{:X}, V{:X}", x, y),
+        Op::AND => format!("AND V{:X}, V{:X}", x, y),
+        Op::XOR => format!("XOR V{:X}, V{:X}", x, y),
+        Op::ADDR => format!("ADD V{:X}, V{:X}", x, y),
+        Op::SUB => format!("SUB V{:X}, V{:X}", x, y),
+        Op::SHR => format!("SHR V{:X}, V{:X}", x, y),
+        Op::SUBN => format!("SUBN V{:X}, V{:X}", x, y),
+        Op::SHL => format!("SHL V{:X}, V{:X}", x, y),
+        Op::SNER => format!("SNE V{:X}, V{:X}", x, y),
+        Op::LDI => format!("LD I, {}", address(nnn)),
+        Op::JPA => format!("JP V0, {}", address(nnn)),
+        Op::RND => format!("RND V{:X}, 0x{:02X}", x, kk),
+        Op::DRW => format!("DRW V{:X}, V{:X}, {}", x, y, nibble),
+        Op::SKP => format!("SKP V{:X}", x),
+        Op::SKNP => format!("SKNP V{:X}", x),
+        Op::LDD => format!("LD V{:X}, DT", x),
+        Op::LDK => format!("LD V{:X}, K", x),
+        Op::LDDT => format!("LD DT, V{:X}", x),
+        Op::LDST => format!("LD ST, V{:X}", x),
+        Op::ADDI => format!("ADD I, V{:X}", x),
+        Op::LDF => format!("LD F, V{:X}", x),
+        Op::LDB => format!("LD B, V{:X}", x),
+        Op::LDII => format!("LD [I], V{:X}", x),
+        Op::LDVX => format!("LD V{:X}, [I]", x),
+        Op::SCD => format!("SCD {}", nibble),
+        Op::SCR => "SCR".to_string(),
+        Op::SCL => "SCL".to_string(),
+        Op::EXIT => "EXIT".to_string(),
+        Op::LOW => "LOW".to_string(),
+        Op::HIGH => "HIGH".to_string(),
+        Op::LDHF => format!("LD HF, V{:X}", x),
+        Op::SRPL => format!("LD R, V{:X}", x),
+        Op::LRPL => format!("LD V{:X}, R", x),
+        Op::SCU => format!("SCU {}", nibble),
+        Op::SVR => format!("SAVE V{:X} - V{:X}", x, y),
+        Op::LDRG => format!("LOAD V{:X} - V{:X}", x, y),
+        Op::LDIL => match next {
+            Some(target) => format!("LD I, LONG {}", address(target)),
+            None => "LD I, LONG ????".to_string(),
+        },
+        Op::PLN => format!("PLANE {}", x),
+        Op::AUD => "AUDIO".to_string(),
+        Op::PITCH => format!("PITCH V{:X}", x),
+    }
+}
+
+// Size in bytes of the instruction starting with this opcode
+pub fn instruction_length(opcode: u16) -> usize {
+    if opcode == 0xF000 { 4 } else { 2 }
+}
+
+// Which bytes of a ROM are reachable code, and the addresses worth labelling
+pub struct Analysis {
+    pub code: Vec<bool>,
+    pub code_labels: BTreeSet<u16>,
+    pub data_labels: BTreeSet<u16>,
+}
+
+// Follow every path of execution from the entry point. Bytes that are never
+// reached as an instruction are treated as data.
+pub fn analyze(rom: &[u8], base: u16) -> Analysis {
+    let word = |address: u16| -> Option<u16> {
+        let offset = address.checked_sub(base)? as usize;
+        let bytes = rom.get(offset..offset + 2)?;
+        Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    };
+
+    let mut analysis = Analysis {
+        code: vec![false; rom.len()],
+        code_labels: BTreeSet::new(),
+        data_labels: BTreeSet::new(),
+    };
+    let mut pending = vec![base];
+    analysis.code_labels.insert(base);
+
+    while let Some(mut address) = pending.pop() {
+        while let Some(opcode) = word(address) {
+            let offset = (address - base) as usize;
+            let length = instruction_length(opcode);
+            if analysis.code[offset] || offset + length > rom.len() {
+                break;
+            }
+            let op = match Op::decode(opcode) {
+                Some(op) => op,
+                None => break,
+            };
+            for byte in analysis.code[offset..offset + length].iter_mut() {
+                *byte = true;
+            }
+            let next = address.wrapping_add(length as u16);
+            match op {
+                Op::JP => {
+                    analysis.code_labels.insert(Op::nnn(opcode));
+                    pending.push(Op::nnn(opcode));
+                    break;
+                }
+                Op::CALL => {
+                    analysis.code_labels.insert(Op::nnn(opcode));
+                    pending.push(Op::nnn(opcode));
+                }
+                // The target of a computed jump is unknown, only the table is labelled
+                Op::JPA => {
+                    analysis.code_labels.insert(Op::nnn(opcode));
+                    break;
+                }
+                Op::RET | Op::EXIT => break,
+                Op::SE | Op::SNE | Op::SER | Op::SNER | Op::SKP | Op::SKNP => {
+                    let skipped = word(next).map_or(2, instruction_length);
+                    pending.push(next.wrapping_add(skipped as u16));
+                }
+                Op::LDI => {
+                    analysis.data_labels.insert(Op::nnn(opcode));
+                }
+                Op::LDIL => {
+                    if let Some(target) = word(address.wrapping_add(2)) {
+                        analysis.data_labels.insert(target);
+                    }
+                }
+                _ => {}
+            }
+            address = next;
+        }
+    }
+    analysis
+}
+
+// Produce an assembly listing with addresses, raw bytes, labels and data blocks
+pub fn listing(rom: &[u8], base: u16) -> String {
+    let analysis = analyze(rom, base);
+    let end = base as usize + rom.len();
+    let in_rom = |address: u16| (base as usize..end).contains(&(address as usize));
+
+    // Only label addresses inside the ROM; code labels win over data labels
+    let mut labels = BTreeMap::new();
+    for address in analysis.data_labels.iter().filter(|a| in_rom(**a)) {
+        labels.insert(*address, format!("data_{:03X}", address));
+    }
+    for address in analysis.code_labels.iter().filter(|a| in_rom(**a)) {
+        labels.insert(*address, format!("label_{:03X}", address));
+    }
+    let name = |address: u16| labels.get(&address)
+        .cloned()
+        .unwrap_or_else(|| format!("0x{:03X}", address));
+
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = base.wrapping_add(offset as u16);
+        if let Some(label) = labels.get(&address) {
+            let _ = writeln!(out, "{}:", label);
+        }
+        if analysis.code[offset] {
+            let opcode = ((rom[offset] as u16) << 8) | rom[offset + 1] as u16;
+            let length = instruction_length(opcode);
+            let next = rom.get(offset + 2..offset + 4)
+                .map(|bytes| ((bytes[0] as u16) << 8) | bytes[1] as u16);
+            let raw: Vec<String> = rom[offset..offset + length].iter().map(|b| format!("{:02X}", b)).collect();
+            let _ = writeln!(out, "{:04X}  {:<12}  {}", address, raw.join(" "), disassemble_with(opcode, next, &name));
+            offset += length;
+        } else {
+            // Group data into rows of up to 4 bytes, split at labels and code
+            let mut row = vec![rom[offset]];
+            while row.len() < 4 {
+                let next = offset + row.len();
+                let next_address = base.wrapping_add(next as u16);
+                if next >= rom.len() || analysis.code[next] || labels.contains_key(&next_address) {
+                    break;
+                }
+                row.push(rom[next]);
+            }
+            let raw: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            let values: Vec<String> = row.iter().map(|b| format!("0x{:02X}", b)).collect();
+            let _ = writeln!(out, "{:04X}  {:<12}  DB {}", address, raw.join(" "), values.join(", "));
+            offset += row.len();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chip8_mnemonics() {
+        let cases = [
+            (0x00E0, "CLS"), (0x00EE, "RET"), (0x1234, "JP 0x234"), (0x2ABC, "CALL 0xABC"),
+            (0x3A1F, "SE VA, 0x1F"), (0x4B20, "SNE VB, 0x20"), (0x5120, "SE V1, V2"),
+            (0x6C7F, "LD VC, 0x7F"), (0x7D01, "ADD VD, 0x01"), (0x8120, "LD V1, V2"),
+            (0x8121, "OR V1, V2"), (0x8122, "AND V1, V2"), (0x8123, "XOR V1, V2"),
+            (0x8124, "ADD V1, V2"), (0x8125, "SUB V1, V2"), (0x8126, "SHR V1, V2"),
+            (0x8127, "SUBN V1, V2"), (0x812E, "SHL V1, V2"), (0x9120, "SNE V1, V2"),
+            (0xA300, "LD I, 0x300"), (0xB400, "JP V0, 0x400"), (0xC3FF, "RND V3, 0xFF"),
+            (0xD125, "DRW V1, V2, 5"), (0xE49E, "SKP V4"), (0xE4A1, "SKNP V4"),
+            (0xF507, "LD V5, DT"), (0xF50A, "LD V5, K"), (0xF515, "LD DT, V5"),
+            (0xF518, "LD ST, V5"), (0xF51E, "ADD I, V5"), (0xF529, "LD F, V5"),
+            (0xF533, "LD B, V5"), (0xF555, "LD [I], V5"), (0xF565, "LD V5, [I]"),
+        ];
+        for (opcode, mnemonic) in cases {
+            assert_eq!(disassemble(opcode, None), mnemonic, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn super_chip_mnemonics() {
+        let cases = [
+            (0x00C4, "SCD 4"), (0x00FB, "SCR"), (0x00FC, "SCL"), (0x00FD, "EXIT"),
+            (0x00FE, "LOW"), (0x00FF, "HIGH"), (0xD120, "DRW V1, V2, 0"),
+            (0xF630, "LD HF, V6"), (0xF775, "LD R, V7"), (0xF885, "LD V8, R"),
+        ];
+        for (opcode, mnemonic) in cases {
+            assert_eq!(disassemble(opcode, None), mnemonic, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn xo_chip_mnemonics() {
+        let cases = [
+            (0x00D3, "SCU 3"), (0x5132, "SAVE V1 - V3"), (0x5133, "LOAD V1 - V3"),
+            (0xF201, "PLANE 2"), (0xF002, "AUDIO"), (0xF93A, "PITCH V9"),
+        ];
+        for (opcode, mnemonic) in cases {
+            assert_eq!(disassemble(opcode, None), mnemonic, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn long_index_load_is_one_instruction() {
+        assert_eq!(disassemble(0xF000, Some(0xBEEF)), "LD I, LONG 0xBEEF");
+        assert_eq!(disassemble(0xF000, None), "LD I, LONG ????");
+        assert_eq!(instruction_length(0xF000), 4);
+        assert_eq!(instruction_length(0xF001), 2);
+        let listing = listing(&[0xF0, 0x00, 0x02, 0x06, 0x12, 0x04, 0xAA], 0x200);
+        assert_eq!(listing, "\
+label_200:
+0200  F0 00 02 06   LD I, LONG data_206
+label_204:
+0204  12 04         JP label_204
+data_206:
+0206  AA            DB 0xAA
+");
+    }
+
+    #[test]
+    fn unknown_opcodes_are_data_words() {
+        assert_eq!(disassemble(0x5121, None), "DW 0x5121");
+        assert_eq!(disassemble(0xE1FF, None), "DW 0xE1FF");
+    }
+
+    // LD I, sprite; CALL draw; SE V0, 0; loop: JP loop; draw: DRW; RET; sprite
+    const PROGRAM: [u8; 15] = [
+        0xA2, 0x0C, 0x22, 0x08, 0x30, 0x00, 0x12, 0x06,
+        0xD0, 0x15, 0x00, 0xEE, 0xF0, 0x90, 0xF0,
+    ];
+
+    #[test]
+    fn analysis_follows_calls_jumps_and_skips() {
+        let analysis = analyze(&PROGRAM, 0x200);
+        assert_eq!(analysis.code, [vec![true; 12], vec![false; 3]].concat());
+        assert_eq!(analysis.code_labels.into_iter().collect::<Vec<_>>(), [0x200, 0x206, 0x208]);
+        assert_eq!(analysis.data_labels.into_iter().collect::<Vec<_>>(), [0x20C]);
+    }
+
+    #[test]
+    fn computed_jumps_and_invalid_opcodes_stop_the_analysis() {
+        // JP V0, table; a word that is never reached; table: JP table
+        let analysis = analyze(&[0xB2, 0x04, 0x12, 0x34, 0x12, 0x04], 0x200);
+        assert_eq!(analysis.code, [true, true, false, false, false, false]);
+        assert!(analysis.code_labels.contains(&0x204));
+        let analysis = analyze(&[0x60, 0x01, 0xFF, 0xFF, 0x00, 0xE0], 0x200);
+        assert_eq!(analysis.code, [true, true, false, false, false, false]);
+    }
+
+    #[test]
+    fn listing_labels_code_and_data() {
+        assert_eq!(listing(&PROGRAM, 0x200), "\
+label_200:
+0200  A2 0C         LD I, data_20C
+0202  22 08         CALL label_208
+0204  30 00         SE V0, 0x00
+label_206:
+0206  12 06         JP label_206
+label_208:
+0208  D0 15         DRW V0, V1, 5
+020A  00 EE         RET
+data_20C:
+020C  F0 90 F0      DB 0xF0, 0x90, 0xF0
+");
+    }
+
+    #[test]
+    fn addresses_outside_the_rom_are_not_labelled() {
+        assert_eq!(listing(&[0xA0, 0x50, 0x13, 0x00], 0x200), "\
+label_200:
+0200  A0 50         LD I, 0x050
+0202  13 00         JP 0x300
+");
+    }
+}
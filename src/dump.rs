@@ -0,0 +1,75 @@
+use crate::cpu::Cpu;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Characters used for the four palette entries in ASCII art
+const ASCII: [char; 4] = ['.', '#', '+', '%'];
+
+// Image formats the framebuffer can be written as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Pbm,
+    Ascii,
+}
+
+impl Format {
+    // Pick the format from the file extension, falling back to ASCII art
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("png") => Format::Png,
+            Some(extension) if extension.eq_ignore_ascii_case("pbm") => Format::Pbm,
+            _ => Format::Ascii,
+        }
+    }
+}
+
+// One line per row, one character per pixel
+pub fn ascii(cpu: &Cpu) -> String {
+    let mut out = String::with_capacity((cpu.width() + 1) * cpu.height());
+    for row in cpu.framebuffer().chunks(cpu.width()) {
+        out.extend(row.iter().map(|pixel| ASCII[*pixel as usize & 3]));
+        out.push('\n');
+    }
+    out
+}
+
+// Plain (P1) bitmap where any lit plane counts as black
+pub fn pbm(cpu: &Cpu) -> String {
+    let mut out = format!("P1\n{} {}\n", cpu.width(), cpu.height());
+    for row in cpu.framebuffer().chunks(cpu.width()) {
+        let bits: Vec<&str> = row.iter().map(|pixel| if *pixel != 0 { "1" } else { "0" }).collect();
+        out.push_str(&bits.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+// The framebuffer at its native resolution in the given palette
+pub fn png(cpu: &Cpu, palette: &[[u8; 4]; 4]) -> io::Result<Vec<u8>> {
+    let mut frame = vec![0; cpu.width() * cpu.height() * 4];
+    cpu.draw(&mut frame, palette);
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, cpu.width() as u32, cpu.height() as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&frame).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)?;
+    Ok(out)
+}
+
+// Write the framebuffer in the format matching the path; "-" prints ASCII art
+pub fn write<P: AsRef<Path>>(path: P, cpu: &Cpu, palette: &[[u8; 4]; 4]) -> io::Result<()> {
+    let path = path.as_ref();
+    if path == Path::new("-") {
+        return io::stdout().write_all(ascii(cpu).as_bytes());
+    }
+    match Format::from_path(path) {
+        Format::Png => fs::write(path, png(cpu, palette)?),
+        Format::Pbm => fs::write(path, pbm(cpu)),
+        Format::Ascii => fs::write(path, ascii(cpu)),
+    }
+}
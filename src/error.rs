@@ -0,0 +1,31 @@
+use std::error::Error;
+use std::fmt;
+
+// Faults the interpreter can run into while executing a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chip8Error {
+    InvalidOpcode { address: u16, opcode: u16 },
+    StackOverflow { address: u16 },
+    StackUnderflow { address: u16 },
+    MemoryOutOfBounds { address: usize },
+    RomTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::InvalidOpcode { address, opcode } =>
+                write!(f, "invalid opcode {:04X} at {:03X}", opcode, address),
+            Chip8Error::StackOverflow { address } =>
+                write!(f, "stack overflow on CALL at {:03X}", address),
+            Chip8Error::StackUnderflow { address } =>
+                write!(f, "stack underflow on RET at {:03X}", address),
+            Chip8Error::MemoryOutOfBounds { address } =>
+                write!(f, "memory access out of bounds at {:X}", address),
+            Chip8Error::RomTooLarge { size, max } =>
+                write!(f, "ROM is {} bytes but at most {} bytes fit in memory", size, max),
+        }
+    }
+}
+
+impl Error for Chip8Error {}
@@ -0,0 +1,72 @@
+use crate::audio::{AudioSink, Tone};
+use crate::cpu::Cpu;
+use crate::error::Chip8Error;
+use crate::memory::Memory;
+
+// Why a headless run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    // The requested number of cycles ran
+    Cycles,
+    // The program executed 00FD
+    Exit,
+    // A 1NNN jumped to itself, which is how most test ROMs finish
+    InfiniteLoop { address: u16 },
+}
+
+// Run up to `cycles` instructions as fast as possible on a virtual 60 Hz clock
+// of `instructions_per_frame` instructions. At the start of every frame the
+// keypad is set from `keys`, and at the end the beeper is reported to `audio`
+// and the timers are decremented.
+pub fn run(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    cycles: u64,
+    instructions_per_frame: u32,
+    keys: &mut dyn FnMut() -> [bool; 16],
+    audio: &mut dyn AudioSink,
+) -> Result<Stop, Chip8Error> {
+    let frame = instructions_per_frame as u64;
+    for cycle in 0..cycles {
+        if let Some(stop) = stopped(cpu, memory) {
+            finish_sound(cpu, audio);
+            return Ok(stop);
+        }
+        if cycle % frame == 0 {
+            cpu.set_keys(keys());
+        }
+        cpu.cycle(memory)?;
+        if (cycle + 1) % frame == 0 {
+            audio.frame(&Tone::of(cpu));
+            cpu.timer();
+        }
+    }
+    match stopped(cpu, memory) {
+        Some(stop) => {
+            finish_sound(cpu, audio);
+            Ok(stop)
+        }
+        None => Ok(Stop::Cycles),
+    }
+}
+
+// Once the program has stopped, play out a beep it started. Only the timers
+// run, so the sound lasts exactly as long as the sound timer asked for.
+pub fn finish_sound(cpu: &mut Cpu, audio: &mut dyn AudioSink) {
+    while cpu.sound_active() {
+        audio.frame(&Tone::of(cpu));
+        cpu.timer();
+    }
+}
+
+// Whether the program has exited or will never do anything else
+pub fn stopped(cpu: &Cpu, memory: &Memory) -> Option<Stop> {
+    if cpu.halted() {
+        return Some(Stop::Exit);
+    }
+    let pc = cpu.pc();
+    match memory.read16(pc as usize) {
+        Ok(opcode) if opcode == 0x1000 | pc => Some(Stop::InfiniteLoop { address: pc }),
+        _ => None,
+    }
+}
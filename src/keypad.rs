@@ -0,0 +1,239 @@
+use winit::event::VirtualKeyCode;
+use winit_input_helper::WinitInputHelper;
+
+// Which host key stands in for each of the 16 CHIP-8 keys
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    keys: [VirtualKeyCode; 16],
+}
+
+impl Keymap {
+    // `keys[n]` is the host key for CHIP-8 key n
+    pub fn new(keys: [VirtualKeyCode; 16]) -> Self {
+        Self { keys }
+    }
+
+    pub fn key(&self, chip8_key: u8) -> VirtualKeyCode {
+        self.keys[chip8_key as usize & 0xF]
+    }
+
+    pub fn set_key(&mut self, chip8_key: u8, key: VirtualKeyCode) {
+        self.keys[chip8_key as usize & 0xF] = key;
+    }
+
+    // The CHIP-8 key a host key is bound to, if any
+    pub fn chip8_key(&self, key: VirtualKeyCode) -> Option<u8> {
+        self.keys.iter().position(|k| *k == key).map(|n| n as u8)
+    }
+
+    // Keypad state from the keys held in the window
+    pub fn state(&self, input: &WinitInputHelper) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for (pressed, key) in keys.iter_mut().zip(self.keys.iter()) {
+            *pressed = input.key_held(*key);
+        }
+        keys
+    }
+}
+
+// The COSMAC VIP keypad laid over the left of a QWERTY keyboard:
+//
+//   1 2 3 C      1 2 3 4
+//   4 5 6 D  ->  Q W E R
+//   7 8 9 E      A S D F
+//   A 0 B F      Z X C V
+impl Default for Keymap {
+    fn default() -> Self {
+        use VirtualKeyCode::*;
+        Self::new([
+            X, Key1, Key2, Key3,
+            Q, W, E, A,
+            S, D, Z, C,
+            Key4, R, F, V,
+        ])
+    }
+}
+
+// Host keys for the emulator's own controls
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hotkeys {
+    pub quit: VirtualKeyCode,
+    pub pause: VirtualKeyCode,
+    pub reset: VirtualKeyCode,
+    pub save_state: VirtualKeyCode,
+    pub load_state: VirtualKeyCode,
+    pub previous_slot: VirtualKeyCode,
+    pub next_slot: VirtualKeyCode,
+    pub speed_up: VirtualKeyCode,
+    pub speed_down: VirtualKeyCode,
+    pub turbo: VirtualKeyCode,
+    pub rewind: VirtualKeyCode,
+    pub next_theme: VirtualKeyCode,
+}
+
+impl Hotkeys {
+    // Every hotkey with the name it has in the config file
+    pub fn named(&self) -> [(&'static str, VirtualKeyCode); 12] {
+        [
+            ("quit", self.quit),
+            ("pause", self.pause),
+            ("reset", self.reset),
+            ("save_state", self.save_state),
+            ("load_state", self.load_state),
+            ("previous_slot", self.previous_slot),
+            ("next_slot", self.next_slot),
+            ("speed_up", self.speed_up),
+            ("speed_down", self.speed_down),
+            ("turbo", self.turbo),
+            ("rewind", self.rewind),
+            ("next_theme", self.next_theme),
+        ]
+    }
+
+    // Bind the hotkey called `name`, false if there is no such hotkey
+    pub fn set(&mut self, name: &str, key: VirtualKeyCode) -> bool {
+        let hotkey = match name {
+            "quit" => &mut self.quit,
+            "pause" => &mut self.pause,
+            "reset" => &mut self.reset,
+            "save_state" => &mut self.save_state,
+            "load_state" => &mut self.load_state,
+            "previous_slot" => &mut self.previous_slot,
+            "next_slot" => &mut self.next_slot,
+            "speed_up" => &mut self.speed_up,
+            "speed_down" => &mut self.speed_down,
+            "turbo" => &mut self.turbo,
+            "rewind" => &mut self.rewind,
+            "next_theme" => &mut self.next_theme,
+            _ => return false,
+        };
+        *hotkey = key;
+        true
+    }
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self {
+            quit: VirtualKeyCode::Escape,
+            pause: VirtualKeyCode::P,
+            reset: VirtualKeyCode::F2,
+            save_state: VirtualKeyCode::F5,
+            load_state: VirtualKeyCode::F9,
+            previous_slot: VirtualKeyCode::F6,
+            next_slot: VirtualKeyCode::F7,
+            speed_up: VirtualKeyCode::PageUp,
+            speed_down: VirtualKeyCode::PageDown,
+            turbo: VirtualKeyCode::Tab,
+            rewind: VirtualKeyCode::Back,
+            next_theme: VirtualKeyCode::F3,
+        }
+    }
+}
+
+// Check that no host key is bound to two things at once
+pub fn check_conflicts(keymap: &Keymap, hotkeys: &Hotkeys) -> Result<(), String> {
+    let mut bindings: Vec<(String, VirtualKeyCode)> = keymap.keys.iter()
+        .enumerate()
+        .map(|(n, key)| (format!("keypad {:X}", n), *key))
+        .collect();
+    bindings.extend(hotkeys.named().iter().map(|(name, key)| (format!("hotkey {}", name), *key)));
+    for (i, (first, key)) in bindings.iter().enumerate() {
+        if let Some((second, _)) = bindings[i + 1..].iter().find(|(_, other)| other == key) {
+            return Err(format!("{:?} is bound to both {} and {}", key, first, second));
+        }
+    }
+    Ok(())
+}
+
+// Host keys by the names used in config files, which are winit's key names
+const KEY_NAMES: &[(&str, VirtualKeyCode)] = {
+    use VirtualKeyCode::*;
+    &[
+        ("A", A), ("B", B), ("C", C), ("D", D), ("E", E), ("F", F), ("G", G),
+        ("H", H), ("I", I), ("J", J), ("K", K), ("L", L), ("M", M), ("N", N),
+        ("O", O), ("P", P), ("Q", Q), ("R", R), ("S", S), ("T", T), ("U", U),
+        ("V", V), ("W", W), ("X", X), ("Y", Y), ("Z", Z),
+        ("Key0", Key0), ("Key1", Key1), ("Key2", Key2), ("Key3", Key3), ("Key4", Key4),
+        ("Key5", Key5), ("Key6", Key6), ("Key7", Key7), ("Key8", Key8), ("Key9", Key9),
+        ("F1", F1), ("F2", F2), ("F3", F3), ("F4", F4), ("F5", F5), ("F6", F6),
+        ("F7", F7), ("F8", F8), ("F9", F9), ("F10", F10), ("F11", F11), ("F12", F12),
+        ("Escape", Escape), ("Tab", Tab), ("Back", Back), ("Return", Return), ("Space", Space),
+        ("Up", Up), ("Down", Down), ("Left", Left), ("Right", Right),
+        ("PageUp", PageUp), ("PageDown", PageDown), ("Home", Home), ("End", End),
+        ("Insert", Insert), ("Delete", Delete),
+        ("Numpad0", Numpad0), ("Numpad1", Numpad1), ("Numpad2", Numpad2), ("Numpad3", Numpad3),
+        ("Numpad4", Numpad4), ("Numpad5", Numpad5), ("Numpad6", Numpad6), ("Numpad7", Numpad7),
+        ("Numpad8", Numpad8), ("Numpad9", Numpad9),
+        ("NumpadAdd", NumpadAdd), ("NumpadSubtract", NumpadSubtract),
+        ("NumpadMultiply", NumpadMultiply), ("NumpadDivide", NumpadDivide),
+        ("NumpadDecimal", NumpadDecimal), ("NumpadEnter", NumpadEnter),
+        ("LShift", LShift), ("RShift", RShift), ("LControl", LControl), ("RControl", RControl),
+        ("LAlt", LAlt), ("RAlt", RAlt),
+        ("Minus", Minus), ("Equals", Equals), ("LBracket", LBracket), ("RBracket", RBracket),
+        ("Backslash", Backslash), ("Semicolon", Semicolon), ("Apostrophe", Apostrophe),
+        ("Grave", Grave), ("Comma", Comma), ("Period", Period), ("Slash", Slash),
+    ]
+};
+
+// Look up a host key by name, ignoring case. Digits may be written bare, and
+// Enter, Esc and Backspace are accepted for Return, Escape and Back.
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    let name = match name.to_ascii_lowercase().as_str() {
+        "enter" => "Return".to_string(),
+        "esc" => "Escape".to_string(),
+        "backspace" => "Back".to_string(),
+        digit if digit.len() == 1 && digit.as_bytes()[0].is_ascii_digit() => format!("Key{}", digit),
+        _ => name.to_string(),
+    };
+    KEY_NAMES.iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(&name))
+        .map(|(_, key)| *key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_do_not_conflict() {
+        assert_eq!(check_conflicts(&Keymap::default(), &Hotkeys::default()), Ok(()));
+    }
+
+    #[test]
+    fn conflicts_are_found() {
+        let mut keymap = Keymap::default();
+        keymap.set_key(0xF, VirtualKeyCode::Key1);
+        assert_eq!(
+            check_conflicts(&keymap, &Hotkeys::default()),
+            Err("Key1 is bound to both keypad 1 and keypad F".to_string())
+        );
+        let mut hotkeys = Hotkeys::default();
+        assert!(hotkeys.set("turbo", VirtualKeyCode::F2));
+        assert_eq!(
+            check_conflicts(&Keymap::default(), &hotkeys),
+            Err("F2 is bound to both hotkey reset and hotkey turbo".to_string())
+        );
+    }
+
+    #[test]
+    fn key_names_ignore_case_and_have_aliases() {
+        assert_eq!(key_from_name("space"), Some(VirtualKeyCode::Space));
+        assert_eq!(key_from_name("PAGEUP"), Some(VirtualKeyCode::PageUp));
+        assert_eq!(key_from_name("Enter"), Some(VirtualKeyCode::Return));
+        assert_eq!(key_from_name("esc"), Some(VirtualKeyCode::Escape));
+        assert_eq!(key_from_name("Backspace"), Some(VirtualKeyCode::Back));
+        assert_eq!(key_from_name("7"), Some(VirtualKeyCode::Key7));
+        assert_eq!(key_from_name("key7"), Some(VirtualKeyCode::Key7));
+        assert_eq!(key_from_name("10"), None);
+        assert_eq!(key_from_name("Nope"), None);
+    }
+
+    #[test]
+    fn keymap_looks_up_both_ways() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.key(0xC), VirtualKeyCode::Key4);
+        assert_eq!(keymap.chip8_key(VirtualKeyCode::Key4), Some(0xC));
+        assert_eq!(keymap.chip8_key(VirtualKeyCode::Space), None);
+    }
+}
@@ -0,0 +1,24 @@
+pub mod memory;
+pub mod op;
+pub mod cpu;
+pub mod chip8;
+pub mod assembler;
+pub mod audio;
+pub mod config;
+pub mod debugger;
+pub mod disasm;
+pub mod dump;
+pub mod error;
+pub mod headless;
+pub mod keypad;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod persistence;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod scheduler;
+pub mod tone_queue;
+pub mod wav;
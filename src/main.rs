@@ -1,24 +1,290 @@
-mod memory;
-mod op;
-mod cpu;
-mod chip8;
-mod audio;
-
-use chip8::CHIP8;
+use anyhow::Context;
+use chip8::audio::{AudioSink, NullSink, Waveform, WAVEFORMS};
+use chip8::chip8::{Settings, CHIP8};
+use chip8::config::Config;
+use chip8::headless::Stop;
+use chip8::movie::Movie;
+use chip8::palette::{self, Palette};
+use chip8::persistence::Persistence;
+use chip8::quirks::{Quirks, PROFILES};
+use chip8::scheduler::{MAX_SPEED, MIN_SPEED};
+use chip8::wav::WavSink;
+use clap::Parser;
 use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// A CHIP-8 interpreter
+#[derive(Parser)]
+#[clap(version, about)]
+struct Args {
+    /// Path to the ROM, or Octo source ending in .8o, to run
+    rom: PathBuf,
+
+    /// Number of instructions executed per 60 Hz frame
+    #[clap(short, long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    ipf: u32,
+
+    /// Emulation speed multiplier, from 0.125 (slow motion) to 8 (turbo)
+    #[clap(long, default_value_t = 1.0, value_parser = parse_speed)]
+    speed: f64,
+
+    /// Window scale factor relative to the 64x32 display
+    #[clap(short, long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+
+    /// Color theme: classic, green, amber, lcd or octo; cycle with F3 while running
+    #[clap(long, value_parser = parse_theme)]
+    theme: Option<Palette>,
+
+    /// Up to four comma-separated RRGGBB colors for the background, plane 1, plane 2 and both planes
+    #[clap(long, value_parser = parse_colors)]
+    palette: Option<Colors>,
+
+    /// Color of lit pixels as RRGGBB hex, optionally after a # or 0x
+    #[clap(long, value_parser = palette::parse_color)]
+    foreground: Option<[u8; 4]>,
+
+    /// Color of unlit pixels as RRGGBB hex, optionally after a # or 0x
+    #[clap(long, value_parser = palette::parse_color)]
+    background: Option<[u8; 4]>,
+
+    /// Flicker reduction: off, vblank, phosphor[:DECAY] fading 0-1 a frame, or blend[:FRAMES]
+    #[clap(long, value_parser = Persistence::parse)]
+    persistence: Option<Persistence>,
+
+    /// Quirk profile: legacy, vip, chip-48, super-chip or xo-chip. Legacy keeps the behaviour of
+    /// earlier versions of this interpreter.
+    #[clap(short, long, default_value = "legacy", value_parser = parse_quirks)]
+    quirks: Quirks,
+
+    /// Whether FX0A takes a key when it is pressed or once it is released, overriding the quirk profile
+    #[clap(long, value_parser = parse_key_wait)]
+    key_wait: Option<bool>,
+
+    /// Seed for the random number generator, for reproducible runs
+    #[clap(long)]
+    seed: Option<u32>,
+
+    /// Generate random numbers with the COSMAC VIP interpreter's method. The numbers differ from a
+    /// real VIP's, which read the interpreter's own code.
+    #[clap(long)]
+    vip_random: bool,
+
+    /// Record the keypad to a movie file that replays the run exactly
+    #[clap(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay a movie file recorded with --record
+    #[clap(long)]
+    replay: Option<PathBuf>,
+
+    /// Config file with key bindings, by default chip8/config.toml in the user's config directory
+    #[clap(long)]
+    config: Option<PathBuf>,
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+    /// Seconds of history kept for rewinding with backspace, 0 disables it
+    #[clap(long, default_value_t = 10)]
+    rewind: u32,
 
-    let mut chip = CHIP8::new();
-    match chip.load_rom("./roms/audio.ch8") {
-        Ok(()) => println!("successfully read rom"),
-        Err(a) => panic!(a)
+    /// Pitch of the beeper in Hz
+    #[clap(long, value_parser = parse_frequency)]
+    frequency: Option<f32>,
+
+    /// Shape of the beep: square, triangle, sawtooth or sine
+    #[clap(long, value_parser = parse_waveform)]
+    waveform: Option<Waveform>,
+
+    /// Beeper volume from 0 to 1
+    #[clap(long, value_parser = parse_volume)]
+    volume: Option<f32>,
+
+    /// Disable the beeper
+    #[clap(short, long)]
+    mute: bool,
+
+    /// Start in borderless fullscreen
+    #[clap(short, long)]
+    fullscreen: bool,
+
+    /// Start paused and control execution from a debugger prompt on stdin
+    #[clap(short, long)]
+    debug: bool,
+
+    /// Run without opening a window, audio device or keyboard
+    #[clap(long)]
+    headless: bool,
+
+    /// Stop a headless run after this many instructions, timed by a virtual 60 Hz clock
+    #[clap(long, requires = "headless")]
+    cycles: Option<u64>,
+
+    /// Write the final framebuffer of a headless run as .png, .pbm or ASCII art; '-' prints it
+    #[clap(long, requires = "headless")]
+    dump: Option<PathBuf>,
+
+    /// Render the sound of a headless run to a WAV file
+    #[clap(long, requires = "headless")]
+    wav: Option<PathBuf>,
+}
+
+fn parse_theme(value: &str) -> Result<Palette, String> {
+    palette::theme(value)
+        .ok_or_else(|| format!("unknown theme '{}', expected one of: {}", value, palette::theme_names().join(", ")))
+}
+
+// The leading palette entries given with --palette
+#[derive(Clone)]
+struct Colors(Vec<[u8; 4]>);
+
+fn parse_colors(value: &str) -> Result<Colors, String> {
+    let colors = value.split(',')
+        .map(|color| palette::parse_color(color.trim()))
+        .collect::<Result<Vec<_>, _>>()?;
+    palette::set_colors(&mut [[0; 4]; 4], &colors)?;
+    Ok(Colors(colors))
+}
+
+fn parse_speed(value: &str) -> Result<f64, String> {
+    let speed: f64 = value.parse().map_err(|_| format!("'{}' is not a number", value))?;
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        return Err(format!("speed must be between {} and {}", MIN_SPEED, MAX_SPEED));
     }
-    chip.load_font();
+    Ok(speed)
+}
 
-    chip.start();
+fn parse_frequency(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(hz) if hz > 0.0 => Ok(hz),
+        _ => Err(format!("'{}' is not a frequency in Hz", value)),
+    }
+}
+
+fn parse_waveform(value: &str) -> Result<Waveform, String> {
+    Waveform::from_name(value)
+        .ok_or_else(|| format!("unknown waveform '{}', expected one of: {}", value, WAVEFORMS.join(", ")))
+}
 
+fn parse_volume(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(volume) if (0.0..=1.0).contains(&volume) => Ok(volume),
+        _ => Err(format!("volume must be a number from 0 to 1, got '{}'", value)),
+    }
+}
+
+fn parse_quirks(value: &str) -> Result<Quirks, String> {
+    Quirks::from_profile(value)
+        .ok_or_else(|| format!("unknown quirk profile '{}', expected one of: {}", value, PROFILES.join(", ")))
+}
 
+// True for release, the COSMAC VIP behaviour
+fn parse_key_wait(value: &str) -> Result<bool, String> {
+    match value {
+        "press" => Ok(false),
+        "release" => Ok(true),
+        _ => Err(format!("expected 'press' or 'release', got '{}'", value)),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    // `chip8 run game.8o` is accepted as a synonym for `chip8 game.8o`
+    let mut argv: Vec<OsString> = env::args_os().collect();
+    if argv.get(1).is_some_and(|arg| arg == "run") {
+        argv.remove(1);
+    }
+    let args = Args::parse_from(argv);
+
+    let mut settings = Settings {
+        instructions_per_frame: args.ipf,
+        scale: args.scale,
+        mute: args.mute,
+        fullscreen: args.fullscreen,
+        quirks: args.quirks,
+        rewind_seconds: args.rewind,
+        debug: args.debug,
+        speed: args.speed,
+        seed: args.seed,
+        vip_random: args.vip_random,
+        ..Settings::default()
+    };
+    if let Some(release) = args.key_wait {
+        settings.quirks.key_release = release;
+    }
+
+    // An explicitly named config has to exist, the default one is optional
+    let config_path = args.config.clone()
+        .or_else(|| Config::default_path().filter(|path| path.exists()));
+    if let Some(path) = &config_path {
+        let config = Config::load(path)
+            .with_context(|| format!("could not read config '{}'", path.display()))?;
+        config.apply(&mut settings, &args.rom)
+            .with_context(|| format!("invalid config '{}'", path.display()))?;
+    }
+
+    // Command line options win over the config file
+    if let Some(frequency) = args.frequency {
+        settings.audio.frequency = frequency;
+    }
+    if let Some(waveform) = args.waveform {
+        settings.audio.waveform = waveform;
+    }
+    if let Some(volume) = args.volume {
+        settings.audio.volume = volume;
+    }
+    if let Some(theme) = args.theme {
+        settings.palette = theme;
+    }
+    if let Some(Colors(colors)) = &args.palette {
+        palette::set_colors(&mut settings.palette, colors).map_err(anyhow::Error::msg)?;
+    }
+    if let Some(background) = args.background {
+        settings.palette[0] = background;
+    }
+    if let Some(foreground) = args.foreground {
+        settings.palette[1] = foreground;
+    }
+    if let Some(persistence) = args.persistence {
+        settings.persistence = persistence;
+    }
+
+    let audio_settings = settings.audio;
+    let mut chip = CHIP8::with_settings(settings);
+    chip.load_rom(&args.rom)
+        .with_context(|| format!("could not load ROM '{}'", args.rom.display()))?;
+    chip.load_font();
+
+    if let Some(path) = &args.replay {
+        let movie = Movie::load(path)
+            .with_context(|| format!("could not load movie '{}'", path.display()))?;
+        chip.replay(movie)
+            .with_context(|| format!("could not replay '{}'", path.display()))?;
+    }
+    if let Some(path) = &args.record {
+        chip.record(path);
+    }
+
+    if args.headless {
+        // Keep the recording of a run that faulted, it is the one worth reporting
+        let mut audio: Box<dyn AudioSink> = match &args.wav {
+            Some(path) => Box::new(WavSink::new(path, audio_settings)),
+            None => Box::new(NullSink),
+        };
+        let result = chip.start_headless(args.cycles, audio.as_mut());
+        chip.finish_movie().context("could not save the movie")?;
+        if let Some(path) = &args.wav {
+            audio.finish().with_context(|| format!("could not write '{}'", path.display()))?;
+        }
+        let stop = result?;
+        if let Stop::InfiniteLoop { address } = stop {
+            eprintln!("stopped: program jumps to itself at {:03X}", address);
+        }
+        if let Some(path) = &args.dump {
+            chip.dump(path)
+                .with_context(|| format!("could not write '{}'", path.display()))?;
+        }
+    } else {
+        chip.start();
+    }
 
+    Ok(())
 }
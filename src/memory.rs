@@ -1,27 +1,195 @@
-const MEMORY_SIZE: usize = 4096;
+use crate::error::Chip8Error;
+use crate::quirks::Quirks;
+use crate::savestate::{self, StateReader, StateWriter};
+use std::cell::Cell;
+use std::io;
 
+// The original 4 KiB, which XO-CHIP extends to the full 16-bit address space
+pub const MEMORY_SIZE: usize = 0x1000;
+pub const EXTENDED_MEMORY_SIZE: usize = 0x10000;
+
+// Programs are loaded at 0x200, the fonts sit at the start of memory
+pub const PROGRAM_START: usize = 0x200;
+pub const BIG_FONT_START: usize = 0x50;
+
+const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// 8x10 digits used by SUPER-CHIP's FX30, with A-F as added by XO-CHIP
+const BIG_FONT: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// A debugger watchpoint on a single address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: usize,
+    pub read: bool,
+    pub write: bool,
+}
+
+// The access that triggered a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: usize,
+    pub access: Access,
+    pub value: u8,
+}
+
+#[derive(Clone)]
 pub struct Memory {
-    mem: [u8; MEMORY_SIZE],
+    mem: Vec<u8>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 impl Memory {
+    // 4 KiB of memory
     pub fn new() -> Self {
+        Self::with_size(MEMORY_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Self {
         Self {
-            mem: [0; MEMORY_SIZE]
+            mem: vec![0; size],
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+        }
+    }
+
+    // As much memory as the machine the quirks describe has
+    pub fn for_quirks(quirks: Quirks) -> Self {
+        Self::with_size(if quirks.extended_memory { EXTENDED_MEMORY_SIZE } else { MEMORY_SIZE })
+    }
+
+    pub fn size(&self) -> usize {
+        self.mem.len()
+    }
+
+    pub fn set(&mut self, index: usize, value: u8) -> Result<(), Chip8Error> {
+        self.watch(index, Access::Write, value);
+        match self.mem.get_mut(index) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(Chip8Error::MemoryOutOfBounds { address: index }),
+        }
+    }
+
+    // Instruction fetches use read16 and do not trigger watchpoints
+    pub fn read16(&self, index: usize) -> Result<u16, Chip8Error> {
+        match (self.mem.get(index), self.mem.get(index + 1)) {
+            (Some(high), Some(low)) => Ok(((*high as u16) << 8) | *low as u16),
+            (None, _) => Err(Chip8Error::MemoryOutOfBounds { address: index }),
+            (_, None) => Err(Chip8Error::MemoryOutOfBounds { address: index + 1 }),
+        }
+    }
+
+    pub fn read8(&self, index: usize) -> Result<u8, Chip8Error> {
+        let value = self.mem.get(index)
+            .copied()
+            .ok_or(Chip8Error::MemoryOutOfBounds { address: index })?;
+        self.watch(index, Access::Read, value);
+        Ok(value)
+    }
+
+    // Read a byte without triggering watchpoints, for debugger output
+    pub fn peek(&self, index: usize) -> Option<u8> {
+        self.mem.get(index).copied()
+    }
+
+    fn watch(&self, address: usize, access: Access, value: u8) {
+        let hit = self.watchpoints.iter().any(|watchpoint| {
+            watchpoint.address == address && match access {
+                Access::Read => watchpoint.read,
+                Access::Write => watchpoint.write,
+            }
+        });
+        if hit && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit { address, access, value }));
+        }
+    }
+
+    pub fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+        self.watchpoints = watchpoints;
+    }
+
+    // The first watched access since the last call, if any
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    // Copy a program image into memory at 0x200
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Chip8Error> {
+        let max = self.mem.len() - PROGRAM_START;
+        if rom.len() > max {
+            return Err(Chip8Error::RomTooLarge { size: rom.len(), max });
         }
+        self.mem[PROGRAM_START..PROGRAM_START + rom.len()].copy_from_slice(rom);
+        Ok(())
     }
 
-    pub fn set(&mut self, index: usize, value: u8) {
-        self.mem[index] = value;
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.mem);
     }
 
-    pub fn read16(&mut self, index: usize) -> u16 {
-        let number = ((self.mem[index] as u16) << 8) | self.mem[index + 1] as u16;
-        return number;
+    pub fn load_state(input: &mut StateReader) -> io::Result<Self> {
+        let bytes = input.bytes()?;
+        if bytes.len() != MEMORY_SIZE && bytes.len() != EXTENDED_MEMORY_SIZE {
+            return Err(savestate::invalid("memory size does not match"));
+        }
+        let mut memory = Memory::with_size(bytes.len());
+        memory.mem.copy_from_slice(bytes);
+        Ok(memory)
     }
 
-    pub fn read8(&mut self, index: usize) -> u8 {
-        let number = self.mem[index];
-        return number;
+    // Copy the hexadecimal font sprites to the start of memory
+    pub fn load_font(&mut self) {
+        self.mem[..FONT.len()].copy_from_slice(&FONT);
+        self.mem[BIG_FONT_START..BIG_FONT_START + BIG_FONT.len()].copy_from_slice(&BIG_FONT);
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -0,0 +1,265 @@
+use crate::quirks::Quirks;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Movies start with a magic number and a format version, then the header
+// fields below in little-endian order, then one 16-bit key mask per frame.
+const MAGIC: &[u8; 4] = b"C8MV";
+pub const VERSION: u16 = 1;
+const HEADER_LENGTH: usize = 4 + 2 + 8 + 4 + 1 + 1 + 4;
+
+// Everything besides the keypad that a replay needs to match the recording
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub rom_hash: u64,
+    pub seed: u32,
+    pub vip_random: bool,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+}
+
+// Keypad state for every 60 Hz frame of a run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub header: Header,
+    pub frames: Vec<u16>,
+}
+
+impl Movie {
+    pub fn new(header: Header) -> Self {
+        Self { header, frames: Vec::new() }
+    }
+
+    pub fn push(&mut self, keys: [bool; 16]) {
+        self.frames.push(key_mask(keys));
+    }
+
+    pub fn keys(&self, frame: usize) -> Option<[bool; 16]> {
+        self.frames.get(frame).map(|mask| keys_from_mask(*mask))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LENGTH + self.frames.len() * 2);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.header.rom_hash.to_le_bytes());
+        out.extend_from_slice(&self.header.seed.to_le_bytes());
+        out.push(u8::from(self.header.vip_random));
+        out.push(self.header.quirks.bits());
+        out.extend_from_slice(&self.header.instructions_per_frame.to_le_bytes());
+        for mask in &self.frames {
+            out.extend_from_slice(&mask.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        if data.len() < HEADER_LENGTH || &data[..4] != MAGIC {
+            return Err(invalid("not a movie file"));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}, expected {}", version, VERSION)));
+        }
+        let header = Header {
+            rom_hash: u64::from_le_bytes(data[6..14].try_into().unwrap_or_default()),
+            seed: u32::from_le_bytes(data[14..18].try_into().unwrap_or_default()),
+            vip_random: data[18] != 0,
+            quirks: Quirks::from_bits(data[19]),
+            instructions_per_frame: u32::from_le_bytes(data[20..24].try_into().unwrap_or_default()),
+        };
+        if header.instructions_per_frame == 0 {
+            return Err(invalid("zero instructions per frame"));
+        }
+        let body = &data[HEADER_LENGTH..];
+        if body.len() & 1 != 0 {
+            return Err(invalid("truncated frame"));
+        }
+        let frames = body.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+        Ok(Self { header, frames })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Movie::from_bytes(&fs::read(path)?)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid movie: {}", message))
+}
+
+// Bit N is set while key N is held
+pub fn key_mask(keys: [bool; 16]) -> u16 {
+    keys.iter().enumerate().fold(0, |mask, (key, held)| mask | (u16::from(*held) << key))
+}
+
+pub fn keys_from_mask(mask: u16) -> [bool; 16] {
+    let mut keys = [false; 16];
+    for (key, held) in keys.iter_mut().enumerate() {
+        *held = mask & (1 << key) != 0;
+    }
+    keys
+}
+
+// 64-bit FNV-1a, enough to tell ROMs apart
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+// A movie being recorded or replayed alongside a run
+pub enum Session {
+    Recording { movie: Movie, path: PathBuf },
+    Playback { movie: Movie, frame: usize },
+}
+
+impl Session {
+    // The keypad state for the next frame: the recorded one during playback,
+    // otherwise the live one, which is recorded if a recording is running.
+    // Once a playback runs out the live keypad takes over.
+    pub fn keys(&mut self, live: [bool; 16]) -> [bool; 16] {
+        match self {
+            Session::Recording { movie, .. } => {
+                movie.push(live);
+                live
+            }
+            Session::Playback { movie, frame } => {
+                let keys = movie.keys(*frame);
+                *frame += 1;
+                keys.unwrap_or(live)
+            }
+        }
+    }
+
+    // Whether a playback has used up all of its recorded frames
+    pub fn finished(&self) -> bool {
+        match self {
+            Session::Recording { .. } => false,
+            Session::Playback { movie, frame } => *frame >= movie.frames.len(),
+        }
+    }
+
+    // Write out a recording; playback has nothing to save
+    pub fn finish(&self) -> io::Result<()> {
+        match self {
+            Session::Recording { movie, path } => movie.save(path),
+            Session::Playback { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullSink;
+    use crate::chip8::{Settings, CHIP8};
+    use std::env;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("chip8-movie-{}-{}", process::id(), name))
+    }
+
+    fn machine(rom: &str, settings: Settings) -> CHIP8 {
+        let mut chip = CHIP8::with_settings(settings);
+        chip.load_font();
+        chip.load_rom(Path::new(env!("CARGO_MANIFEST_DIR")).join("roms").join(rom)).unwrap();
+        chip
+    }
+
+    // The complete machine state after a run, to compare two runs by
+    fn state(chip: &CHIP8, name: &str) -> Vec<u8> {
+        let path = temp_path(name);
+        chip.save_state(&path).unwrap();
+        let state = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        state
+    }
+
+    fn header() -> Header {
+        Header { rom_hash: 1, seed: 2, vip_random: true, quirks: Quirks::xo_chip(), instructions_per_frame: 10 }
+    }
+
+    #[test]
+    fn a_replay_repeats_the_recorded_run() {
+        // The coin flips come from the random number generator
+        let settings = Settings { quirks: Quirks::cosmac_vip(), ..Settings::default() };
+        let mut recorded = machine("coinflip.ch8", settings);
+        let path = temp_path("coinflip.c8m");
+        recorded.record(&path);
+        recorded.start_headless(Some(5000), &mut NullSink).unwrap();
+        recorded.finish_movie().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let movie = Movie::from_bytes(&data).unwrap();
+        assert_eq!(movie.to_bytes(), data);
+        assert_eq!(movie.frames.len(), 500);
+
+        // The replay takes the quirks and seed from the movie
+        let mut replayed = machine("coinflip.ch8", Settings::default());
+        replayed.replay(movie).unwrap();
+        replayed.start_headless(Some(5000), &mut NullSink).unwrap();
+        assert_eq!(state(&replayed, "replayed"), state(&recorded, "recorded"));
+    }
+
+    #[test]
+    fn movies_survive_bytes() {
+        let mut movie = Movie::new(header());
+        movie.push([false; 16]);
+        let mut keys = [false; 16];
+        keys[0xA] = true;
+        keys[0xF] = true;
+        movie.push(keys);
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+        assert_eq!(movie.keys(1), Some(keys));
+        assert_eq!(movie.keys(2), None);
+    }
+
+    #[test]
+    fn invalid_movies_are_rejected() {
+        let mut movie = Movie::new(header());
+        movie.push([true; 16]);
+        let data = movie.to_bytes();
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert!(Movie::from_bytes(&bad_magic).is_err());
+
+        let mut bad_version = data.clone();
+        bad_version[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(Movie::from_bytes(&bad_version).is_err());
+
+        assert!(Movie::from_bytes(&data[..data.len() - 1]).is_err());
+        assert!(Movie::from_bytes(&data[..HEADER_LENGTH - 1]).is_err());
+    }
+
+    #[test]
+    fn movies_of_another_rom_are_rejected() {
+        let mut recorded = machine("coinflip.ch8", Settings::default());
+        let path = temp_path("other.c8m");
+        recorded.record(&path);
+        recorded.finish_movie().unwrap();
+        let movie = Movie::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut other = machine("maze.ch8", Settings::default());
+        assert!(other.replay(movie).is_err());
+    }
+
+    #[test]
+    fn playback_falls_back_to_the_live_keypad() {
+        let mut movie = Movie::new(header());
+        movie.push([true; 16]);
+        let mut session = Session::Playback { movie, frame: 0 };
+        assert!(!session.finished());
+        assert_eq!(session.keys([false; 16]), [true; 16]);
+        assert!(session.finished());
+        assert_eq!(session.keys([false; 16]), [false; 16]);
+    }
+}
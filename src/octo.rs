@@ -0,0 +1,865 @@
+use crate::assembler::AsmError;
+use crate::memory::{EXTENDED_MEMORY_SIZE, PROGRAM_START};
+use std::collections::HashMap;
+
+// Compiles Octo, the structured assembly language most modern CHIP-8 programs
+// are written in. Programs start executing at the `main` label.
+//
+//   :alias x v0
+//   :const SPEED 2
+//   :calc LIMIT { 64 - 8 }
+//   :macro step reg { reg += SPEED }
+//
+//   : main
+//     loop
+//       step x
+//       if x >= LIMIT begin
+//         x := 0
+//       else
+//         sprite x x 5
+//       end
+//     again
+
+// Guards against macros that expand into themselves
+const MAX_EXPANSIONS: usize = 10000;
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+fn error(token: &Token, message: String) -> AsmError {
+    AsmError::new(token.line, token.column, message)
+}
+
+// Tokens are separated by whitespace and `#` starts a comment
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut start = None;
+        for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+            match (c.is_whitespace(), start) {
+                (true, Some(begin)) => {
+                    tokens.push(Token {
+                        text: line[begin..i].to_string(),
+                        line: number + 1,
+                        column: line[..begin].chars().count() + 1,
+                    });
+                    start = None;
+                }
+                (false, None) => start = Some(i),
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+// Jumps and calls only hold 12-bit addresses, so code after an `:org` above
+// 0xFFF can only be reached with `i := long`
+fn short_address(address: u16, token: &Token) -> Result<u16, AsmError> {
+    if address > 0xFFF {
+        return Err(error(token, format!("address {:#X} does not fit in 12 bits", address)));
+    }
+    Ok(address)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+        lower.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Open `if ... begin` and `loop` blocks, with the offsets of jumps still to patch
+enum Block {
+    If { token: Token, jump: usize, has_else: bool },
+    Loop { token: Token, start: u16, breaks: Vec<usize> },
+}
+
+// A reference to a label that was not defined yet when it was used
+struct Fixup {
+    token: Token,
+    offset: usize,
+    long: bool,
+}
+
+// Compiled comparisons: instructions that set up VF, then the skip used when
+// the condition is false (for `then`) or true (for `begin` and `while`)
+struct Condition {
+    setup: Vec<u16>,
+    skip_if_false: u16,
+    skip_if_true: u16,
+}
+
+// Compile Octo source into a ROM loaded at PROGRAM_START
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source),
+        position: 0,
+        rom: Vec::new(),
+        here: PROGRAM_START,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        expansions: 0,
+    };
+    compiler.compile()?;
+    Ok(compiler.rom)
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    position: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn compile(&mut self) -> Result<(), AsmError> {
+        // Jump over whatever comes before main, unless main comes first
+        let starts_with_main = matches!(self.tokens.as_slice(), [colon, main, ..] if colon.text == ":" && main.text == "main");
+        if !starts_with_main {
+            let token = Token { text: "main".to_string(), line: 1, column: 1 };
+            self.emit_address(0x1000, &token)?;
+        }
+
+        while self.position < self.tokens.len() {
+            self.statement()?;
+        }
+
+        if let Some(block) = self.blocks.last() {
+            return Err(match block {
+                Block::If { token, .. } => error(token, "`if` without a matching `end`".to_string()),
+                Block::Loop { token, .. } => error(token, "`loop` without a matching `again`".to_string()),
+            });
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let address = match self.labels.get(&fixup.token.text) {
+                Some(address) => *address,
+                None if fixup.token.text == "main" => return Err(error(&fixup.token, "program has no `main` label".to_string())),
+                None => return Err(error(&fixup.token, format!("undefined label '{}'", fixup.token.text))),
+            };
+            if fixup.long {
+                self.rom[fixup.offset..fixup.offset + 2].copy_from_slice(&address.to_be_bytes());
+            } else {
+                self.patch(fixup.offset, address, &fixup.token)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.clone())
+            }
+            None => {
+                let last = self.tokens.last().cloned().unwrap_or(Token { text: String::new(), line: 1, column: 1 });
+                Err(error(&last, "unexpected end of file".to_string()))
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(error(&token, format!("expected '{}', got '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn emit(&mut self, byte: u8, token: &Token) -> Result<(), AsmError> {
+        if self.here >= EXTENDED_MEMORY_SIZE {
+            return Err(error(token, "program does not fit in memory".to_string()));
+        }
+        let offset = self.here - PROGRAM_START;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit16(&mut self, word: u16, token: &Token) -> Result<(), AsmError> {
+        let [high, low] = word.to_be_bytes();
+        self.emit(high, token)?;
+        self.emit(low, token)
+    }
+
+    // Emit an instruction with a 12-bit address operand, patched later if the
+    // label is not known yet
+    fn emit_address(&mut self, opcode: u16, token: &Token) -> Result<(), AsmError> {
+        match self.known_value(token)? {
+            Some(address) if (0..=0xFFF).contains(&address) => self.emit16(opcode | address as u16, token),
+            Some(address) => Err(error(token, format!("address {:#X} does not fit in 12 bits", address))),
+            None => {
+                self.fixups.push(Fixup { token: token.clone(), offset: self.here - PROGRAM_START, long: false });
+                self.emit16(opcode, token)
+            }
+        }
+    }
+
+    // Point the jump at `offset` to `address`
+    fn patch(&mut self, offset: usize, address: u16, token: &Token) -> Result<(), AsmError> {
+        let address = short_address(address, token)?;
+        let opcode = u16::from_be_bytes([self.rom[offset], self.rom[offset + 1]]) & 0xF000;
+        self.rom[offset..offset + 2].copy_from_slice(&(opcode | address).to_be_bytes());
+        Ok(())
+    }
+
+    // A number, constant or label that is already defined. Other names are
+    // left for labels defined further down.
+    fn known_value(&self, token: &Token) -> Result<Option<i64>, AsmError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(Some(value));
+        }
+        if let Some(value) = self.constants.get(&token.text) {
+            return Ok(Some(*value));
+        }
+        if let Some(address) = self.labels.get(&token.text) {
+            return Ok(Some(*address as i64));
+        }
+        if self.register(token).is_some() || token.text.starts_with(':') {
+            return Err(error(token, format!("expected a value, got '{}'", token.text)));
+        }
+        Ok(None)
+    }
+
+    fn value(&self, token: &Token, min: i64, max: i64) -> Result<u16, AsmError> {
+        let value = self.known_value(token)?
+            .ok_or_else(|| error(token, format!("undefined name '{}'", token.text)))?;
+        if value < min || value > max {
+            return Err(error(token, format!("value {} is out of range {}..={}", value, min, max)));
+        }
+        Ok(value as u16)
+    }
+
+    fn byte(&self, token: &Token) -> Result<u16, AsmError> {
+        Ok(self.value(token, -128, 0xFF)? & 0xFF)
+    }
+
+    fn nibble(&self, token: &Token) -> Result<u16, AsmError> {
+        self.value(token, 0, 0xF)
+    }
+
+    fn register(&self, token: &Token) -> Option<u16> {
+        if let Some(x) = self.aliases.get(&token.text) {
+            return Some(*x);
+        }
+        let digit = token.text.strip_prefix('v').or_else(|| token.text.strip_prefix('V'))?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u16::from_str_radix(digit, 16).ok()
+    }
+
+    fn expect_register(&mut self) -> Result<u16, AsmError> {
+        let token = self.next()?;
+        self.register(&token).ok_or_else(|| error(&token, format!("expected a register, got '{}'", token.text)))
+    }
+
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if parse_number(&token.text).is_some() || self.register(&token).is_some() {
+            return Err(error(&token, format!("'{}' cannot be used as a name", token.text)));
+        }
+        if self.labels.contains_key(&token.text) || self.constants.contains_key(&token.text) {
+            return Err(error(&token, format!("'{}' is already defined", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let token = self.next()?;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.labels.insert(name.text, self.here as u16);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let x = self.expect_register()?;
+                self.aliases.insert(name.text, x);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.known_value(&value)?
+                    .ok_or_else(|| error(&value, format!("undefined name '{}'", value.text)))?;
+                self.constants.insert(name.text, value);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc()?;
+                self.constants.insert(name.text, value.floor() as i64);
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.calc()?.floor() as i64 as u16 & 0xFF
+                } else {
+                    let value = self.next()?;
+                    self.byte(&value)?
+                };
+                self.emit(value as u8, &token)?;
+            }
+            ":org" => {
+                let address = self.next()?;
+                let address = self.value(&address, PROGRAM_START as i64, EXTENDED_MEMORY_SIZE as i64 - 1)?;
+                self.here = address as usize;
+            }
+            ":macro" => self.define_macro()?,
+            ":call" => {
+                let target = self.next()?;
+                self.emit_address(0x2000, &target)?;
+            }
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ";" | "return" => self.emit16(0x00EE, &token)?,
+            "clear" => self.emit16(0x00E0, &token)?,
+            "exit" => self.emit16(0x00FD, &token)?,
+            "lores" => self.emit16(0x00FE, &token)?,
+            "hires" => self.emit16(0x00FF, &token)?,
+            "scroll-right" => self.emit16(0x00FB, &token)?,
+            "scroll-left" => self.emit16(0x00FC, &token)?,
+            "audio" => self.emit16(0xF002, &token)?,
+            "scroll-down" | "scroll-up" | "plane" => {
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                let opcode = match token.text.as_str() {
+                    "scroll-down" => 0x00C0 | n,
+                    "scroll-up" => 0x00D0 | n,
+                    _ => 0xF001 | (n << 8),
+                };
+                self.emit16(opcode, &token)?;
+            }
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.expect_register()?;
+                let opcode = match token.text.as_str() {
+                    "bcd" => 0xF033,
+                    "saveflags" => 0xF075,
+                    _ => 0xF085,
+                };
+                self.emit16(opcode | (x << 8), &token)?;
+            }
+            "save" | "load" => {
+                let x = self.expect_register()?;
+                let save = token.text == "save";
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.expect_register()?;
+                    let opcode = if save { 0x5002 } else { 0x5003 };
+                    self.emit16(opcode | (x << 8) | (y << 4), &token)?;
+                } else {
+                    let opcode = if save { 0xF055 } else { 0xF065 };
+                    self.emit16(opcode | (x << 8), &token)?;
+                }
+            }
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let n = self.next()?;
+                let n = self.nibble(&n)?;
+                self.emit16(0xD000 | (x << 8) | (y << 4) | n, &token)?;
+            }
+            "jump" | "jump0" => {
+                let target = self.next()?;
+                let opcode = if token.text == "jump" { 0x1000 } else { 0xB000 };
+                self.emit_address(opcode, &target)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.expect_register()?;
+                let opcode = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.emit16(opcode | (x << 8), &token)?;
+            }
+            "i" => self.index(&token)?,
+            "if" => self.conditional(&token)?,
+            "else" => match self.blocks.pop() {
+                Some(Block::If { token: open, jump, has_else: false }) => {
+                    let end = self.here - PROGRAM_START;
+                    self.emit16(0x1000, &token)?;
+                    self.patch(jump, self.here as u16, &token)?;
+                    self.blocks.push(Block::If { token: open, jump: end, has_else: true });
+                }
+                _ => return Err(error(&token, "`else` without a matching `if ... begin`".to_string())),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => self.patch(jump, self.here as u16, &token)?,
+                _ => return Err(error(&token, "`end` without a matching `if ... begin`".to_string())),
+            },
+            "loop" => self.blocks.push(Block::Loop { token: token.clone(), start: self.here as u16, breaks: Vec::new() }),
+            "while" => {
+                let condition = self.condition()?;
+                let breaks = match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) {
+                    Some(Block::Loop { breaks, .. }) => breaks,
+                    _ => return Err(error(&token, "`while` outside of a loop".to_string())),
+                };
+                breaks.push(self.here - PROGRAM_START + 2 * condition.setup.len() + 2);
+                for opcode in condition.setup {
+                    self.emit16(opcode, &token)?;
+                }
+                self.emit16(condition.skip_if_true, &token)?;
+                self.emit16(0x1000, &token)?;
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, breaks, .. }) => {
+                    let start = short_address(start, &token)?;
+                    self.emit16(0x1000 | start, &token)?;
+                    for jump in breaks {
+                        self.patch(jump, self.here as u16, &token)?;
+                    }
+                }
+                _ => return Err(error(&token, "`again` without a matching `loop`".to_string())),
+            },
+            _ => {
+                if let Some(x) = self.register(&token) {
+                    return self.assignment(x, &token);
+                }
+                if let Some(value) = parse_number(&token.text) {
+                    if !(-128..=0xFF).contains(&value) {
+                        return Err(error(&token, format!("value {} does not fit in a byte", value)));
+                    }
+                    return self.emit(value as u8, &token);
+                }
+                if self.macros.contains_key(&token.text) {
+                    return self.expand(&token);
+                }
+                if token.text.starts_with(':') || self.constants.contains_key(&token.text) {
+                    return Err(error(&token, format!("unexpected '{}'", token.text)));
+                }
+                // Any other name calls the subroutine with that label
+                self.emit_address(0x2000, &token)?;
+            }
+        }
+        Ok(())
+    }
+
+    // i := addr, i := long addr, i := hex vx, i := bighex vx and i += vx
+    fn index(&mut self, token: &Token) -> Result<(), AsmError> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            "+=" => {
+                let x = self.expect_register()?;
+                self.emit16(0xF01E | (x << 8), token)
+            }
+            ":=" => {
+                let source = self.next()?;
+                match source.text.as_str() {
+                    "hex" | "bighex" => {
+                        let x = self.expect_register()?;
+                        let opcode = if source.text == "hex" { 0xF029 } else { 0xF030 };
+                        self.emit16(opcode | (x << 8), token)
+                    }
+                    "long" => {
+                        let target = self.next()?;
+                        self.emit16(0xF000, token)?;
+                        match self.known_value(&target)? {
+                            Some(address) if (0..=0xFFFF).contains(&address) => self.emit16(address as u16, token),
+                            Some(address) => Err(error(&target, format!("address {:#X} does not fit in 16 bits", address))),
+                            None => {
+                                self.fixups.push(Fixup { token: target.clone(), offset: self.here - PROGRAM_START, long: true });
+                                self.emit16(0, token)
+                            }
+                        }
+                    }
+                    _ => self.emit_address(0xA000, &source),
+                }
+            }
+            _ => Err(error(&op, format!("expected ':=' or '+=', got '{}'", op.text))),
+        }
+    }
+
+    fn assignment(&mut self, x: u16, token: &Token) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let source = self.next()?;
+        let xy = |y: u16, n: u16| 0x8000 | (x << 8) | (y << 4) | n;
+        let opcode = match (op.text.as_str(), self.register(&source)) {
+            (":=", Some(y)) => xy(y, 0x0),
+            ("|=", Some(y)) => xy(y, 0x1),
+            ("&=", Some(y)) => xy(y, 0x2),
+            ("^=", Some(y)) => xy(y, 0x3),
+            ("+=", Some(y)) => xy(y, 0x4),
+            ("-=", Some(y)) => xy(y, 0x5),
+            (">>=", Some(y)) => xy(y, 0x6),
+            ("=-", Some(y)) => xy(y, 0x7),
+            ("<<=", Some(y)) => xy(y, 0xE),
+            (":=", None) => match source.text.as_str() {
+                "key" => 0xF00A | (x << 8),
+                "delay" => 0xF007 | (x << 8),
+                "random" => {
+                    let mask = self.next()?;
+                    0xC000 | (x << 8) | self.byte(&mask)?
+                }
+                _ => 0x6000 | (x << 8) | self.byte(&source)?,
+            },
+            ("+=", None) => 0x7000 | (x << 8) | self.byte(&source)?,
+            ("-=", None) => 0x7000 | (x << 8) | (self.byte(&source)?.wrapping_neg() & 0xFF),
+            _ => return Err(error(&op, format!("invalid operation '{}' '{}'", op.text, source.text))),
+        };
+        self.emit16(opcode, token)
+    }
+
+    fn conditional(&mut self, token: &Token) -> Result<(), AsmError> {
+        let condition = self.condition()?;
+        for opcode in &condition.setup {
+            self.emit16(*opcode, token)?;
+        }
+        let keyword = self.next()?;
+        match keyword.text.as_str() {
+            "then" => self.emit16(condition.skip_if_false, token),
+            "begin" => {
+                self.emit16(condition.skip_if_true, token)?;
+                self.blocks.push(Block::If { token: token.clone(), jump: self.here - PROGRAM_START, has_else: false });
+                self.emit16(0x1000, token)
+            }
+            _ => Err(error(&keyword, format!("expected 'then' or 'begin', got '{}'", keyword.text))),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let x = self.expect_register()?;
+        let op = self.next()?;
+        let simple = |skip_if_false, skip_if_true| Condition { setup: Vec::new(), skip_if_false, skip_if_true };
+        match op.text.as_str() {
+            "key" => return Ok(simple(0xE0A1 | (x << 8), 0xE09E | (x << 8))),
+            "-key" => return Ok(simple(0xE09E | (x << 8), 0xE0A1 | (x << 8))),
+            _ => {}
+        }
+
+        let operand = self.next()?;
+        let y = self.register(&operand);
+        let equal = |y: Option<u16>, this: &Self| -> Result<(u16, u16), AsmError> {
+            Ok(match y {
+                Some(y) => (0x5000 | (x << 8) | (y << 4), 0x9000 | (x << 8) | (y << 4)),
+                None => {
+                    let kk = this.byte(&operand)?;
+                    (0x3000 | (x << 8) | kk, 0x4000 | (x << 8) | kk)
+                }
+            })
+        };
+        match op.text.as_str() {
+            "==" => {
+                let (skip_equal, skip_not_equal) = equal(y, self)?;
+                return Ok(simple(skip_not_equal, skip_equal));
+            }
+            "!=" => {
+                let (skip_equal, skip_not_equal) = equal(y, self)?;
+                return Ok(simple(skip_equal, skip_not_equal));
+            }
+            _ => {}
+        }
+
+        // Ordered comparisons subtract into VF and test the borrow flag:
+        // `<` and `>=` compute vx - operand, `>` and `<=` compute operand - vx
+        let vx_first = match op.text.as_str() {
+            "<" | ">=" => true,
+            ">" | "<=" => false,
+            _ => return Err(error(&op, format!("expected a comparison, got '{}'", op.text))),
+        };
+        let setup = match (y, vx_first) {
+            (Some(y), true) => vec![0x8F00 | (x << 4), 0x8F05 | (y << 4)],
+            (Some(y), false) => vec![0x8F00 | (y << 4), 0x8F05 | (x << 4)],
+            (None, true) => vec![0x6F00 | self.byte(&operand)?, 0x8F07 | (x << 4)],
+            (None, false) => vec![0x6F00 | self.byte(&operand)?, 0x8F05 | (x << 4)],
+        };
+        // VF is 1 when the subtraction did not borrow
+        let no_borrow_means_true = matches!(op.text.as_str(), ">=" | "<=");
+        let (skip_if_false, skip_if_true) = if no_borrow_means_true { (0x3F00, 0x3F01) } else { (0x3F01, 0x3F00) };
+        Ok(Condition { setup, skip_if_false, skip_if_true })
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(token);
+        }
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    // Splice the macro body, with arguments substituted, into the token stream
+    fn expand(&mut self, token: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(error(token, "too many macro expansions, is a macro recursive?".to_string()));
+        }
+        let count = self.macros[&token.text].params.len();
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            args.push(self.next()?);
+        }
+        let definition = &self.macros[&token.text];
+        let body: Vec<Token> = definition.body.iter()
+            .map(|body_token| match definition.params.iter().position(|param| *param == body_token.text) {
+                Some(index) => args[index].clone(),
+                None => body_token.clone(),
+            })
+            .collect();
+        self.tokens.splice(self.position..self.position, body);
+        Ok(())
+    }
+
+    // Evaluate `{ ... }`. As in Octo, operators have no precedence and are
+    // applied right to left; use parentheses to group.
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        self.expect("{")?;
+        let value = self.expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn expression(&mut self) -> Result<f64, AsmError> {
+        let left = self.term()?;
+        let op = match self.peek() {
+            Some(op @ ("+" | "-" | "*" | "/" | "%" | "&" | "|" | "^" | "<<" | ">>" | "min" | "max")) => op.to_string(),
+            _ => return Ok(left),
+        };
+        let token = self.next()?;
+        let right = self.expression()?;
+        let (a, b) = (left as i64, right as i64);
+        Ok(match op.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" if right == 0.0 => return Err(error(&token, "division by zero".to_string())),
+            "/" => left / right,
+            "%" if b == 0 => return Err(error(&token, "division by zero".to_string())),
+            "%" => (a % b) as f64,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << (b & 63)) as f64,
+            ">>" => (a >> (b & 63)) as f64,
+            "min" => left.min(right),
+            _ => left.max(right),
+        })
+    }
+
+    fn term(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        match token.text.as_str() {
+            "(" => {
+                let value = self.expression()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            "-" => Ok(-self.term()?),
+            "~" => Ok(!(self.term()? as i64) as f64),
+            "!" => Ok(if self.term()? == 0.0 { 1.0 } else { 0.0 }),
+            "HERE" => Ok(self.here as f64),
+            _ => match self.known_value(&token)? {
+                Some(value) => Ok(value as f64),
+                None => Err(error(&token, format!("undefined name '{}'", token.text))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::memory::Memory;
+    use crate::quirks::Quirks;
+
+    // Compile `source` and run it until it jumps to itself
+    fn run(source: &str) -> Cpu {
+        let rom = compile(source).unwrap_or_else(|e| panic!("{}", e));
+        let mut memory = Memory::for_quirks(Quirks::xo_chip());
+        memory.load_font();
+        memory.load_rom(&rom).unwrap();
+        let mut cpu = Cpu::with_quirks(Quirks::xo_chip());
+        for _ in 0..10_000 {
+            let pc = cpu.pc();
+            cpu.cycle(&mut memory).unwrap();
+            if cpu.pc() == pc {
+                return cpu;
+            }
+        }
+        panic!("program did not finish");
+    }
+
+    fn compile_error(source: &str) -> AsmError {
+        compile(source).expect_err("source should not compile")
+    }
+
+    #[test]
+    fn if_then_skips_the_next_statement() {
+        let cpu = run("
+            : main
+              v0 := 5
+              if v0 == 5 then v1 := 1
+              if v0 != 5 then v2 := 1
+              if v0 < 6 then v3 := 1
+              if v0 > 6 then v4 := 1
+              loop again
+        ");
+        assert_eq!(cpu.registers()[1..5], [1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn if_begin_else_end_takes_one_branch() {
+        let cpu = run("
+            : main
+              v0 := 2
+              if v0 >= 3 begin
+                v1 := 1
+              else
+                v1 := 2
+              end
+              if v0 <= 3 begin
+                v2 := 1
+              else
+                v2 := 2
+              end
+              loop again
+        ");
+        assert_eq!(cpu.registers()[1..3], [2, 1]);
+    }
+
+    #[test]
+    fn loop_while_again_repeats_until_the_condition_fails() {
+        let cpu = run("
+            : main
+              loop
+                v0 += 1
+                while v0 != 10
+                v1 += 2
+              again
+              loop again
+        ");
+        assert_eq!(cpu.registers()[..2], [10, 18]);
+    }
+
+    #[test]
+    fn macros_substitute_their_arguments() {
+        let cpu = run("
+            :macro add-twice reg n { reg += n reg += n }
+            : main
+              add-twice v2 3
+              add-twice v3 v2
+              loop again
+        ");
+        assert_eq!(cpu.registers()[2..4], [6, 12]);
+    }
+
+    #[test]
+    fn calc_applies_operators_right_to_left() {
+        let cpu = run("
+            :const BASE 3
+            :calc RIGHT { 2 * BASE + 1 }
+            :calc GROUPED { ( 2 * BASE ) + 1 }
+            : main
+              v0 := RIGHT
+              v1 := GROUPED
+              loop again
+        ");
+        assert_eq!(cpu.registers()[..2], [8, 7]);
+    }
+
+    #[test]
+    fn org_places_the_following_code_and_data() {
+        let source = "
+            : main
+              i := table
+              load v1
+              loop again
+            :org 0x300
+            : table 0x12 0x34
+        ";
+        assert_eq!(compile(source).unwrap()[0x100..], [0x12, 0x34]);
+        assert_eq!(run(source).registers()[..2], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn aliases_name_registers() {
+        let cpu = run("
+            :alias counter v4
+            : main
+              counter := 9
+              counter += 1
+              loop again
+        ");
+        assert_eq!(cpu.registers()[4], 10);
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        let e = compile_error(": main\n  v0 := 5\n  v1 := nowhere\n");
+        assert_eq!((e.line, e.column), (3, 9));
+        assert_eq!(e.to_string(), "3:9: undefined name 'nowhere'");
+
+        let e = compile_error(": main\n  loop\n    v0 += 1\n");
+        assert_eq!((e.line, e.column), (2, 3));
+
+        let e = compile_error("v0 := 1\n");
+        assert_eq!(e.message, "program has no `main` label");
+    }
+
+    #[test]
+    fn again_above_0xfff_is_an_error() {
+        let e = compile_error(": main\n:org 0x1000\n  loop again\n");
+        assert_eq!((e.line, e.column), (3, 8));
+        assert!(e.message.contains("12 bits"), "{}", e.message);
+    }
+}
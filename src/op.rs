@@ -33,25 +33,53 @@ pub enum Op {
     LDB,    // Store "BCD"(normal) representation of Vx in memory location  I, I+1 and I+2
     LDII,   // Store registers V0 through Vx in memory starting at location I
     LDVX,   // Read registers V0 through Vx into memory starting at location I
-
+    SCD,    // 00Cn - Scroll the display down n lines (SUPER-CHIP)
+    SCR,    // 00FB - Scroll the display right 4 pixels (SUPER-CHIP)
+    SCL,    // 00FC - Scroll the display left 4 pixels (SUPER-CHIP)
+    EXIT,   // 00FD - Exit the interpreter (SUPER-CHIP)
+    LOW,    // 00FE - Switch to the 64x32 low resolution mode (SUPER-CHIP)
+    HIGH,   // 00FF - Switch to the 128x64 high resolution mode (SUPER-CHIP)
+    LDHF,   // Fx30 - Set I = location of the 8x10 big font sprite for digit Vx (SUPER-CHIP)
+    SRPL,   // Fx75 - Store V0 through Vx in the RPL user flags (SUPER-CHIP)
+    LRPL,   // Fx85 - Read V0 through Vx from the RPL user flags (SUPER-CHIP)
+    SCU,    // 00Dn - Scroll the display up n lines (XO-CHIP)
+    SVR,    // 5xy2 - Store Vx through Vy in memory starting at location I (XO-CHIP)
+    LDRG,   // 5xy3 - Read Vx through Vy from memory starting at location I (XO-CHIP)
+    LDIL,   // F000 nnnn - Set I to the 16-bit address in the following word (XO-CHIP)
+    PLN,    // Fn01 - Select the bitplanes n that drawing, clearing and scrolling act on (XO-CHIP)
+    AUD,    // F002 - Load 16 bytes starting at I into the audio pattern buffer (XO-CHIP)
+    PITCH,  // Fx3A - Set the audio pattern playback pitch to Vx (XO-CHIP)
 }
 
 impl Op {
-    pub fn decode(opcode: u16) -> Self {
-        match opcode & 0xF000 {
-            0x0000 => match opcode & 0xFFFF {
+    // Returns None for opcodes outside the instruction set
+    pub fn decode(opcode: u16) -> Option<Self> {
+        let op = match opcode & 0xF000 {
+            0x0000 => match opcode {
                 0x00E0 => Self::CLR,
                 0x00EE => Self::RET,
-                _ => panic!("Invalid opcode: {:04X}", opcode),
+                0x00FB => Self::SCR,
+                0x00FC => Self::SCL,
+                0x00FD => Self::EXIT,
+                0x00FE => Self::LOW,
+                0x00FF => Self::HIGH,
+                _ if opcode & 0xFFF0 == 0x00C0 => Self::SCD,
+                _ if opcode & 0xFFF0 == 0x00D0 => Self::SCU,
+                _ => return None,
             }
             0x1000 => Self::JP,
             0x2000 => Self::CALL,
             0x3000 => Self::SE,
             0x4000 => Self::SNE,
-            0x5000 => Self::SER,
+            0x5000 => match opcode & 0x000F {
+                0x0000 => Self::SER,
+                0x0002 => Self::SVR,
+                0x0003 => Self::LDRG,
+                _ => return None,
+            },
             0x6000 => Self::LD,
             0x7000 => Self::ADD,
-            0x8000 => Self::decode8(opcode),
+            0x8000 => Self::decode8(opcode)?,
             0x9000 => Self::SNER,
             0xA000 => Self::LDI,
             0xB000 => Self::JPA,
@@ -60,15 +88,16 @@ impl Op {
             0xE000 => match opcode & 0x00FF {
                 0x009E => Self::SKP,
                 0x00A1 => Self::SKNP,
-                _ => panic!("Invalid opcode: {:04X}", opcode),
+                _ => return None,
             }, //multiple
-            0xF000 => Self::decode_f(opcode), // multiple
-            _ => panic!("Invalid opcode: {:04X}", opcode),
-        }
+            0xF000 => Self::decode_f(opcode)?, // multiple
+            _ => return None,
+        };
+        Some(op)
     }
 
-    pub fn decode8(opcode: u16) -> Self {
-        match opcode & 0x000F {
+    pub fn decode8(opcode: u16) -> Option<Self> {
+        let op = match opcode & 0x000F {
             0x0000 => Self::LDR,
             0x0001 => Self::OR,
             0x0002 => Self::AND,
@@ -78,23 +107,32 @@ impl Op {
             0x0006 => Self::SHR,
             0x0007 => Self::SUBN,
             0x000E => Self::SHL,
-            _ => panic!("Invalid opcode: {:04X}", opcode),
-        }
+            _ => return None,
+        };
+        Some(op)
     }
 
-    pub fn decode_f(opcode: u16) -> Self {
-        match opcode & 0x00FF {
+    pub fn decode_f(opcode: u16) -> Option<Self> {
+        let op = match opcode & 0x00FF {
+            0x0000 if opcode == 0xF000 => Self::LDIL,
+            0x0001 => Self::PLN,
+            0x0002 if opcode == 0xF002 => Self::AUD,
             0x0007 => Self::LDD,
             0x000A => Self::LDK,
             0x0015 => Self::LDDT,
             0x0018 => Self::LDST,
             0x001E => Self::ADDI,
             0x0029 => Self::LDF,
+            0x0030 => Self::LDHF,
+            0x003A => Self::PITCH,
             0x0033 => Self::LDB,
             0x0055 => Self::LDII,
             0x0065 => Self::LDVX,
-            _ => panic!("Invalid opcode: {:04X}", opcode),
-        }
+            0x0075 => Self::SRPL,
+            0x0085 => Self::LRPL,
+            _ => return None,
+        };
+        Some(op)
     }
 
     pub fn x(opcode: u16) -> usize {
@@ -110,7 +148,7 @@ impl Op {
     }
 
     pub fn nnn(opcode: u16) -> u16 {
-        (opcode & 0x0FFF) as u16
+        opcode & 0x0FFF
     }
 
     pub fn nibble(opcode: u16) -> usize {
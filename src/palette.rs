@@ -0,0 +1,109 @@
+// Colors for no plane, plane 1, plane 2 and both planes lit, as RGBA
+pub type Palette = [[u8; 4]; 4];
+
+const fn rgb(rgb: u32) -> [u8; 4] {
+    [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 0xFF]
+}
+
+// Built-in palettes, in the order the theme hotkey cycles through them
+pub const THEMES: [(&str, Palette); 5] = [
+    ("classic", [rgb(0x000000), rgb(0xFFFFFF), rgb(0xAAAAAA), rgb(0x555555)]),
+    // A green phosphor monitor
+    ("green", [rgb(0x001100), rgb(0x33FF33), rgb(0x119911), rgb(0x22CC22)]),
+    // An amber monochrome monitor
+    ("amber", [rgb(0x1A0F00), rgb(0xFFB000), rgb(0xA86800), rgb(0xD48C00)]),
+    // The greenish LCD of early handhelds, dark pixels on a light screen
+    ("lcd", [rgb(0x9BBC0F), rgb(0x0F380F), rgb(0x8BAC0F), rgb(0x306230)]),
+    // The defaults of the Octo IDE
+    ("octo", [rgb(0x996600), rgb(0xFFCC00), rgb(0xFF6600), rgb(0x662200)]),
+];
+
+// Look up a theme by one of the names in THEMES
+pub fn theme(name: &str) -> Option<Palette> {
+    THEMES.iter()
+        .find(|(theme, _)| theme.eq_ignore_ascii_case(name))
+        .map(|(_, palette)| *palette)
+}
+
+pub fn theme_names() -> Vec<&'static str> {
+    THEMES.iter().map(|(name, _)| *name).collect()
+}
+
+// Parse a color written as RRGGBB, optionally after a '#' or 0x
+pub fn parse_color(value: &str) -> Result<[u8; 4], String> {
+    let hex = value.strip_prefix('#')
+        .or_else(|| value.strip_prefix("0x"))
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    if hex.len() != 6 {
+        return Err(format!("expected a color as RRGGBB, got '{}'", value));
+    }
+    // from_str_radix would also take a sign
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a hexadecimal color", value));
+    }
+    let color = u32::from_str_radix(hex, 16)
+        .map_err(|_| format!("'{}' is not a hexadecimal color", value))?;
+    Ok(rgb(color))
+}
+
+// Replace the first colors of `palette` with `colors`, at most four of them
+pub fn set_colors(palette: &mut Palette, colors: &[[u8; 4]]) -> Result<(), String> {
+    if colors.is_empty() || colors.len() > palette.len() {
+        return Err(format!("expected 1 to {} colors, got {}", palette.len(), colors.len()));
+    }
+    palette[..colors.len()].copy_from_slice(colors);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_take_an_optional_prefix() {
+        let orange = [0xFF, 0x66, 0x00, 0xFF];
+        assert_eq!(parse_color("FF6600"), Ok(orange));
+        assert_eq!(parse_color("#ff6600"), Ok(orange));
+        assert_eq!(parse_color("0xFF6600"), Ok(orange));
+        assert_eq!(parse_color("0XFF6600"), Ok(orange));
+    }
+
+    #[test]
+    fn invalid_colors_are_rejected() {
+        assert!(parse_color("FF660").is_err());
+        assert!(parse_color("#FF66000").is_err());
+        assert!(parse_color("##FF6600").is_err());
+        assert!(parse_color("GG6600").is_err());
+        assert!(parse_color("+F6600").is_err());
+        assert!(parse_color("").is_err());
+    }
+
+    #[test]
+    fn colors_replace_the_start_of_the_palette() {
+        let mut palette = theme("classic").unwrap();
+        let red = rgb(0xFF0000);
+        set_colors(&mut palette, &[red, red]).unwrap();
+        assert_eq!(palette, [red, red, rgb(0xAAAAAA), rgb(0x555555)]);
+
+        assert!(set_colors(&mut palette, &[]).is_err());
+        assert!(set_colors(&mut palette, &[red; 5]).is_err());
+        assert_eq!(palette[2], rgb(0xAAAAAA));
+    }
+
+    #[test]
+    fn themes_are_found_by_name() {
+        assert_eq!(
+            theme("Octo"),
+            Some([
+                [0x99, 0x66, 0x00, 0xFF],
+                [0xFF, 0xCC, 0x00, 0xFF],
+                [0xFF, 0x66, 0x00, 0xFF],
+                [0x66, 0x22, 0x00, 0xFF],
+            ])
+        );
+        assert_eq!(theme("CLASSIC").map(|palette| palette[1]), Some([0xFF; 4]));
+        assert_eq!(theme("sepia"), None);
+        assert_eq!(theme_names(), ["classic", "green", "amber", "lcd", "octo"]);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::cpu::Cpu;
+use crate::palette::Palette;
+use std::collections::VecDeque;
+
+// Programs move sprites by erasing them with XOR and drawing them again, so a
+// sprite is missing from the screen whenever a vertical blank falls in between.
+// These modes hide that flicker by building the picture from recent blanks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Persistence {
+    // The framebuffer as it is when the window is redrawn
+    Off,
+    // Lit pixels light up at once and fade out like a CRT phosphor, losing
+    // `decay` of their brightness every frame
+    Phosphor { decay: f32 },
+    // Pixels are as bright as the share of the last `frames` frames they were lit in
+    Blend { frames: usize },
+    // The framebuffer as it was at the last vertical blank where the program
+    // had drawn since clearing the display, so frames that are cleared and
+    // redrawn never show up blank
+    Vblank,
+}
+
+pub const PERSISTENCE_MODES: [&str; 4] = ["off", "phosphor", "blend", "vblank"];
+
+const DEFAULT_DECAY: f32 = 0.5;
+const DEFAULT_BLEND_FRAMES: usize = 3;
+const MAX_BLEND_FRAMES: usize = 60;
+
+impl Persistence {
+    // Parse one of PERSISTENCE_MODES. Phosphor and blend may be followed by
+    // their setting, as in "phosphor:0.3" or "blend:4".
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (name, setting) = match value.split_once(':') {
+            Some((name, setting)) => (name, Some(setting)),
+            None => (value, None),
+        };
+        match (name.to_ascii_lowercase().as_str(), setting) {
+            ("off", None) => Ok(Persistence::Off),
+            ("vblank", None) => Ok(Persistence::Vblank),
+            ("phosphor", None) => Ok(Persistence::Phosphor { decay: DEFAULT_DECAY }),
+            ("phosphor", Some(decay)) => match decay.parse::<f32>() {
+                Ok(decay) if decay > 0.0 && decay <= 1.0 => Ok(Persistence::Phosphor { decay }),
+                _ => Err(format!("phosphor decay should be above 0 and at most 1, got '{}'", decay)),
+            },
+            ("blend", None) => Ok(Persistence::Blend { frames: DEFAULT_BLEND_FRAMES }),
+            ("blend", Some(frames)) => match frames.parse::<usize>() {
+                Ok(frames) if (1..=MAX_BLEND_FRAMES).contains(&frames) => Ok(Persistence::Blend { frames }),
+                _ => Err(format!("blend frames should be from 1 to {}, got '{}'", MAX_BLEND_FRAMES, frames)),
+            },
+            _ => Err(format!("unknown persistence '{}', expected one of: {}", value, PERSISTENCE_MODES.join(", "))),
+        }
+    }
+}
+
+// What the window shows, updated from the framebuffer at every vertical blank
+pub struct Display {
+    persistence: Persistence,
+    // Framebuffers of the last frames, newest last, while blending
+    history: VecDeque<Vec<u8>>,
+    // Brightness of plane 1 and plane 2 for every pixel, from 0 to 1
+    levels: Vec<[f32; 2]>,
+}
+
+impl Display {
+    pub fn new(persistence: Persistence) -> Self {
+        Self {
+            persistence,
+            history: VecDeque::new(),
+            levels: Vec::new(),
+        }
+    }
+
+    // Called at the end of every emulated frame
+    pub fn vblank(&mut self, cpu: &Cpu) {
+        let framebuffer = cpu.framebuffer();
+        // Switching between lores and hires starts over
+        if self.levels.len() != framebuffer.len() {
+            self.history.clear();
+            self.levels = vec![[0.0; 2]; framebuffer.len()];
+        }
+        let lit = |pixel: u8, plane: usize| if (pixel >> plane) & 1 != 0 { 1.0 } else { 0.0 };
+
+        match self.persistence {
+            Persistence::Off => {}
+            // Keep the previous picture until something is drawn again
+            Persistence::Vblank if cpu.cleared() => {}
+            Persistence::Vblank => {
+                for (level, pixel) in self.levels.iter_mut().zip(framebuffer) {
+                    *level = [lit(*pixel, 0), lit(*pixel, 1)];
+                }
+            }
+            Persistence::Phosphor { decay } => {
+                for (level, pixel) in self.levels.iter_mut().zip(framebuffer) {
+                    for (plane, brightness) in level.iter_mut().enumerate() {
+                        *brightness = (*brightness * (1.0 - decay)).max(lit(*pixel, plane));
+                    }
+                }
+            }
+            Persistence::Blend { frames } => {
+                if self.history.len() == frames {
+                    self.history.pop_front();
+                }
+                self.history.push_back(framebuffer.to_vec());
+                let share = 1.0 / self.history.len() as f32;
+                for (i, level) in self.levels.iter_mut().enumerate() {
+                    *level = [0.0; 2];
+                    for old in &self.history {
+                        level[0] += lit(old[i], 0) * share;
+                        level[1] += lit(old[i], 1) * share;
+                    }
+                }
+            }
+        }
+    }
+
+    // Render into an RGBA frame the size of the current display mode
+    pub fn draw(&self, cpu: &Cpu, frame: &mut [u8], palette: &Palette) {
+        // Before the first vertical blank in this display mode there is nothing else to show
+        if self.persistence == Persistence::Off || self.levels.len() * 4 != frame.len() {
+            cpu.draw(frame, palette);
+            return;
+        }
+        for (pixel, [plane1, plane2]) in frame.chunks_exact_mut(4).zip(&self.levels) {
+            // Mix the palette entries as if each plane were lit that share of the time
+            let weights = [
+                (1.0 - plane1) * (1.0 - plane2),
+                plane1 * (1.0 - plane2),
+                (1.0 - plane1) * plane2,
+                plane1 * plane2,
+            ];
+            for (channel, value) in pixel.iter_mut().enumerate() {
+                let mixed: f32 = weights.iter()
+                    .zip(palette.iter())
+                    .map(|(weight, color)| weight * color[channel] as f32)
+                    .sum();
+                *value = mixed.round().min(255.0) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    // Runs a program that draws the top row of the font's 0, which lights
+    // pixels 0-3, and then clears the display on every following instruction
+    struct Machine {
+        cpu: Cpu,
+        memory: Memory,
+        display: Display,
+    }
+
+    impl Machine {
+        fn new(persistence: Persistence) -> Self {
+            let mut memory = Memory::new();
+            memory.load_font();
+            memory.load_rom(&[0xA0, 0x00, 0xD0, 0x01, 0x00, 0xE0, 0x00, 0xE0, 0xD0, 0x01]).unwrap();
+            Self { cpu: Cpu::new(), memory, display: Display::new(persistence) }
+        }
+
+        // Run `cycles` instructions and end the frame, returning the level of pixel 0 in plane 1
+        fn frame(&mut self, cycles: usize) -> f32 {
+            for _ in 0..cycles {
+                self.cpu.cycle(&mut self.memory).unwrap();
+            }
+            self.display.vblank(&self.cpu);
+            self.display.levels[0][0]
+        }
+    }
+
+    fn assert_levels(persistence: Persistence, expected: &[f32]) {
+        let mut machine = Machine::new(persistence);
+        let levels: Vec<f32> = [2, 1, 1, 1].iter().map(|cycles| machine.frame(*cycles)).collect();
+        assert_eq!(levels, expected);
+    }
+
+    #[test]
+    fn phosphor_fades_by_the_decay() {
+        assert_levels(Persistence::Phosphor { decay: 0.5 }, &[1.0, 0.5, 0.25, 1.0]);
+        assert_levels(Persistence::Phosphor { decay: 1.0 }, &[1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn blend_averages_the_last_frames() {
+        assert_levels(Persistence::Blend { frames: 2 }, &[1.0, 0.5, 0.0, 0.5]);
+        assert_levels(Persistence::Blend { frames: 4 }, &[1.0, 0.5, 1.0 / 3.0, 0.5]);
+    }
+
+    #[test]
+    fn vblank_skips_frames_that_were_cleared_and_not_redrawn() {
+        assert_levels(Persistence::Vblank, &[1.0, 1.0, 1.0, 1.0]);
+        let mut machine = Machine::new(Persistence::Vblank);
+        machine.frame(3);
+        assert_eq!(machine.display.levels[0], [0.0; 2]);
+    }
+
+    #[test]
+    fn planes_are_tracked_separately() {
+        let mut machine = Machine::new(Persistence::Blend { frames: 2 });
+        machine.frame(2);
+        assert_eq!(machine.display.levels[..5], [[1.0, 0.0], [1.0, 0.0], [1.0, 0.0], [1.0, 0.0], [0.0, 0.0]]);
+    }
+
+    #[test]
+    fn persistence_names_parse() {
+        assert_eq!(Persistence::parse("Off"), Ok(Persistence::Off));
+        assert_eq!(Persistence::parse("vblank"), Ok(Persistence::Vblank));
+        assert_eq!(Persistence::parse("phosphor"), Ok(Persistence::Phosphor { decay: DEFAULT_DECAY }));
+        assert_eq!(Persistence::parse("phosphor:0.25"), Ok(Persistence::Phosphor { decay: 0.25 }));
+        assert_eq!(Persistence::parse("blend:4"), Ok(Persistence::Blend { frames: 4 }));
+        assert!(Persistence::parse("phosphor:0").is_err());
+        assert!(Persistence::parse("blend:61").is_err());
+        assert!(Persistence::parse("vblank:1").is_err());
+        assert!(Persistence::parse("crt").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+// Behaviours that differ between CHIP-8 interpreters. ROMs written for one
+// platform often rely on that platform's interpretation of these instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift Vx in place instead of storing Vy shifted into Vx
+    pub shift: bool,
+    // FX55/FX65 advance I past the last register that was stored or loaded
+    pub load_store_increment: bool,
+    // BNNN jumps to NNN + VX instead of NNN + V0
+    pub jump: bool,
+    // 8XY1/8XY2/8XY3 reset VF to zero
+    pub vf_reset: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clipping: bool,
+    // DXYN waits for the next 60 Hz vertical blank before the program continues
+    pub display_wait: bool,
+    // FX0A completes when the key is released again instead of when it goes down
+    pub key_release: bool,
+    // 64 KiB of memory, with F000 NNNN to point I anywhere in it, instead of 4 KiB
+    pub extended_memory: bool,
+}
+
+pub const PROFILES: [&str; 5] = ["legacy", "vip", "chip-48", "super-chip", "xo-chip"];
+
+impl Quirks {
+    // How this interpreter behaved before quirks could be chosen, and still
+    // does by default: CHIP-48 shifts with BNNN offset by V0 and wrapping sprites
+    pub const fn legacy() -> Self {
+        Self {
+            shift: true,
+            load_store_increment: false,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            key_release: false,
+            extended_memory: false,
+        }
+    }
+
+    // The original interpreter on the RCA COSMAC VIP
+    pub const fn cosmac_vip() -> Self {
+        Self {
+            shift: false,
+            load_store_increment: true,
+            jump: false,
+            vf_reset: true,
+            clipping: true,
+            display_wait: true,
+            key_release: true,
+            extended_memory: false,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators
+    pub const fn chip48() -> Self {
+        Self {
+            shift: true,
+            load_store_increment: false,
+            jump: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            key_release: false,
+            extended_memory: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1, which kept the CHIP-48 behaviour
+    pub const fn super_chip() -> Self {
+        Self::chip48()
+    }
+
+    // XO-CHIP as implemented by Octo
+    pub const fn xo_chip() -> Self {
+        Self {
+            shift: false,
+            load_store_increment: true,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            key_release: true,
+            extended_memory: true,
+        }
+    }
+
+    // Look up a preset by one of the names in PROFILES
+    pub fn from_profile(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "legacy" => Some(Self::legacy()),
+            "vip" | "cosmac-vip" | "chip-8" => Some(Self::cosmac_vip()),
+            "chip-48" | "chip48" => Some(Self::chip48()),
+            "super-chip" | "schip" => Some(Self::super_chip()),
+            "xo-chip" | "xochip" => Some(Self::xo_chip()),
+            _ => None,
+        }
+    }
+
+    // Pack the quirks into one byte, in the order of the fields above
+    pub fn bits(&self) -> u8 {
+        [
+            self.shift, self.load_store_increment, self.jump, self.vf_reset,
+            self.clipping, self.display_wait, self.key_release, self.extended_memory,
+        ]
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, quirk)| bits | (u8::from(*quirk) << i))
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self {
+            shift: bits & 1 != 0,
+            load_store_increment: bits & 2 != 0,
+            jump: bits & 4 != 0,
+            vf_reset: bits & 8 != 0,
+            clipping: bits & 16 != 0,
+            display_wait: bits & 32 != 0,
+            key_release: bits & 64 != 0,
+            extended_memory: bits & 128 != 0,
+        }
+    }
+
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::legacy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_keeps_the_legacy_behaviour() {
+        let quirks = Quirks::default();
+        assert_eq!(quirks, Quirks::legacy());
+        assert!(quirks.shift && !quirks.jump && !quirks.clipping);
+        assert!(!quirks.load_store_increment && !quirks.vf_reset);
+        assert!(!quirks.display_wait && !quirks.key_release && !quirks.extended_memory);
+    }
+
+    #[test]
+    fn every_profile_survives_bits() {
+        for name in PROFILES.iter() {
+            let quirks = Quirks::from_profile(name).unwrap();
+            assert_eq!(Quirks::from_bits(quirks.bits()), quirks, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+use crate::savestate;
+use std::collections::VecDeque;
+
+// Ring buffer of per-frame machine snapshots. Once full, the oldest frame is
+// dropped for every new one. Only the newest frame is kept as a whole save
+// state; every older one is stored as its difference from the frame after it,
+// which is a few bytes for most frames.
+pub struct Rewind {
+    newest: Option<Vec<u8>>,
+    // Oldest first
+    older: VecDeque<Delta>,
+    capacity: usize,
+}
+
+// How to get a frame back from the save state of the frame after it
+enum Delta {
+    // The bytes that differ, as runs of (unchanged u16, changed u16, changed bytes XOR the newer state)
+    Changes(Vec<u8>),
+    // The states differ in length, so they cannot be compared byte by byte
+    Whole(Vec<u8>),
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            newest: None,
+            older: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    // Keep enough frames to rewind the given number of seconds at 60 Hz
+    pub fn with_seconds(seconds: u32) -> Self {
+        Rewind::new(seconds as usize * 60)
+    }
+
+    pub fn push(&mut self, cpu: &Cpu, memory: &Memory) {
+        if self.capacity == 0 {
+            return;
+        }
+        let state = savestate::save(cpu, memory);
+        if let Some(previous) = self.newest.take() {
+            self.older.push_back(Delta::between(&previous, &state));
+            // The newest frame takes up one place too
+            if self.older.len() >= self.capacity {
+                self.older.pop_front();
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    // Take the most recent frame, or None once the buffer is exhausted
+    pub fn pop(&mut self) -> Option<(Cpu, Memory)> {
+        let state = self.newest.take()?;
+        self.newest = self.older.pop_back().map(|delta| delta.apply(&state));
+        savestate::load(&state).ok()
+    }
+}
+
+impl Delta {
+    // Record how to turn `newer` back into `older`
+    fn between(older: &[u8], newer: &[u8]) -> Delta {
+        if older.len() != newer.len() {
+            return Delta::Whole(older.to_vec());
+        }
+        let mut changes = Vec::new();
+        let mut i = 0;
+        while i < older.len() {
+            let start = i;
+            while i < older.len() && i - start < u16::MAX as usize && older[i] == newer[i] {
+                i += 1;
+            }
+            let unchanged = i - start;
+            let start = i;
+            while i < older.len() && i - start < u16::MAX as usize && older[i] != newer[i] {
+                i += 1;
+            }
+            changes.extend_from_slice(&(unchanged as u16).to_le_bytes());
+            changes.extend_from_slice(&((i - start) as u16).to_le_bytes());
+            changes.extend(older[start..i].iter().zip(&newer[start..i]).map(|(old, new)| old ^ new));
+        }
+        Delta::Changes(changes)
+    }
+
+    fn apply(self, newer: &[u8]) -> Vec<u8> {
+        let changes = match self {
+            Delta::Whole(older) => return older,
+            Delta::Changes(changes) => changes,
+        };
+        let mut older = newer.to_vec();
+        let mut position = 0;
+        let mut rest = &changes[..];
+        while rest.len() >= 4 {
+            let unchanged = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+            let changed = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+            position += unchanged;
+            for (byte, xor) in older[position..position + changed].iter_mut().zip(&rest[4..4 + changed]) {
+                *byte ^= xor;
+            }
+            position += changed;
+            rest = &rest[4 + changed..];
+        }
+        older
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A machine whose byte at 0x300 identifies the frame
+    fn frame(number: u8) -> (Cpu, Memory) {
+        let mut memory = Memory::new();
+        memory.load_rom(&[0x60, number]).unwrap();
+        memory.set(0x300, number).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.cycle(&mut memory).unwrap();
+        (cpu, memory)
+    }
+
+    fn push(rewind: &mut Rewind, number: u8) {
+        let (cpu, memory) = frame(number);
+        rewind.push(&cpu, &memory);
+    }
+
+    fn popped(rewind: &mut Rewind) -> Option<u8> {
+        rewind.pop().map(|(_, memory)| memory.read8(0x300).unwrap())
+    }
+
+    #[test]
+    fn frames_come_back_newest_first() {
+        let mut rewind = Rewind::new(10);
+        for number in 1..=3 {
+            push(&mut rewind, number);
+        }
+        assert_eq!(popped(&mut rewind), Some(3));
+        push(&mut rewind, 4);
+        assert_eq!(popped(&mut rewind), Some(4));
+        assert_eq!(popped(&mut rewind), Some(2));
+        assert_eq!(popped(&mut rewind), Some(1));
+        assert_eq!(popped(&mut rewind), None);
+    }
+
+    #[test]
+    fn restored_frames_match_the_saved_state() {
+        let mut rewind = Rewind::new(10);
+        let states: Vec<Vec<u8>> = (1..=3).map(|number| {
+            let (cpu, memory) = frame(number);
+            rewind.push(&cpu, &memory);
+            savestate::save(&cpu, &memory)
+        }).collect();
+        for state in states.iter().rev() {
+            let (cpu, memory) = rewind.pop().unwrap();
+            assert_eq!(&savestate::save(&cpu, &memory), state);
+        }
+    }
+
+    #[test]
+    fn a_full_buffer_drops_the_oldest_frame() {
+        let mut rewind = Rewind::new(3);
+        for number in 1..=5 {
+            push(&mut rewind, number);
+        }
+        assert_eq!(popped(&mut rewind), Some(5));
+        assert_eq!(popped(&mut rewind), Some(4));
+        assert_eq!(popped(&mut rewind), Some(3));
+        assert_eq!(popped(&mut rewind), None);
+    }
+
+    #[test]
+    fn capacity_one_keeps_only_the_newest_frame() {
+        let mut rewind = Rewind::new(1);
+        for number in 1..=3 {
+            push(&mut rewind, number);
+            assert!(rewind.older.is_empty());
+        }
+        assert_eq!(popped(&mut rewind), Some(3));
+        assert_eq!(popped(&mut rewind), None);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut rewind = Rewind::new(0);
+        push(&mut rewind, 1);
+        assert_eq!(popped(&mut rewind), None);
+    }
+
+    #[test]
+    fn states_of_different_lengths_are_kept_whole() {
+        let older = vec![1, 2, 3];
+        let newer = vec![1, 2, 3, 4];
+        assert_eq!(Delta::between(&older, &newer).apply(&newer), older);
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let older = vec![0; 70_000];
+        let mut newer = older.clone();
+        newer[69_999] = 1;
+        newer[..66_000].iter_mut().for_each(|byte| *byte = 2);
+        assert_eq!(Delta::between(&older, &newer).apply(&newer), older);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::memory::{Memory, PROGRAM_START};
+use crate::savestate::{self, StateReader, StateWriter};
+use rand::Rng as _;
+use std::io;
+
+// Xorshift generator for CXKK. Unlike a thread-local generator its state is a
+// plain value, so it can be saved and restored with the rest of the machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    // Seed from the operating system's entropy
+    pub fn new() -> Self {
+        Rng::with_state(rand::thread_rng().gen())
+    }
+
+    pub fn with_state(state: u32) -> Self {
+        // Xorshift never leaves the all-zero state
+        Self { state: if state == 0 { 0x9E37_79B9 } else { state } }
+    }
+
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 24) as u8
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The COSMAC VIP interpreter kept a 16-bit seed. Each CXKK incremented it, read
+// the byte at the seed's low byte in the interpreter's own memory page, and
+// added that to the seed's high byte, which became the random number.
+//
+// The VIP read its interpreter code. That code is not part of this emulator,
+// so the first page of the loaded program stands in for it: the method matches
+// the VIP but the sequence of numbers does not.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VipRng {
+    seed: u16,
+}
+
+impl VipRng {
+    pub fn with_state(seed: u16) -> Self {
+        Self { seed }
+    }
+
+    pub fn state(&self) -> u16 {
+        self.seed
+    }
+
+    pub fn next_u8(&mut self, memory: &Memory) -> u8 {
+        let [high, low] = self.seed.wrapping_add(1).to_be_bytes();
+        let high = high.wrapping_add(memory.peek(PROGRAM_START + low as usize).unwrap_or(0));
+        self.seed = u16::from_be_bytes([high, low]);
+        high
+    }
+}
+
+// Where CXKK gets its random numbers from. The generator is part of the machine
+// state, so a given seed always replays the same numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Random {
+    Xorshift(Rng),
+    Vip(VipRng),
+}
+
+impl Random {
+    // Seeded from the operating system's entropy when no seed is given
+    pub fn xorshift(seed: Option<u32>) -> Self {
+        Random::Xorshift(seed.map_or_else(Rng::new, Rng::with_state))
+    }
+
+    pub fn vip(seed: Option<u32>) -> Self {
+        let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+        Random::Vip(VipRng::with_state(seed as u16))
+    }
+
+    pub fn next_u8(&mut self, memory: &Memory) -> u8 {
+        match self {
+            Random::Xorshift(rng) => rng.next_u8(),
+            Random::Vip(rng) => rng.next_u8(memory),
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        match self {
+            Random::Xorshift(rng) => {
+                out.u8(0);
+                out.u32(rng.state());
+            }
+            Random::Vip(rng) => {
+                out.u8(1);
+                out.u32(rng.state() as u32);
+            }
+        }
+    }
+
+    pub fn load_state(input: &mut StateReader) -> io::Result<Self> {
+        let kind = input.u8()?;
+        let state = input.u32()?;
+        match kind {
+            0 => Ok(Random::Xorshift(Rng::with_state(state))),
+            1 => Ok(Random::Vip(VipRng::with_state(state as u16))),
+            _ => Err(savestate::invalid("unknown random number generator")),
+        }
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Random::xorshift(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn numbers(mut random: Random, memory: &Memory) -> Vec<u8> {
+        (0..32).map(|_| random.next_u8(memory)).collect()
+    }
+
+    fn program() -> Memory {
+        let mut memory = Memory::new();
+        let rom: Vec<u8> = (0..=255).collect();
+        memory.load_rom(&rom).unwrap();
+        memory
+    }
+
+    #[test]
+    fn a_seed_always_gives_the_same_numbers() {
+        let memory = program();
+        for random in [Random::xorshift, Random::vip].iter() {
+            let first = numbers(random(Some(1234)), &memory);
+            assert_eq!(numbers(random(Some(1234)), &memory), first);
+            assert_ne!(numbers(random(Some(4321)), &memory), first);
+        }
+    }
+
+    #[test]
+    fn the_vip_method_adds_a_program_byte_to_the_seed() {
+        let memory = program();
+        let mut rng = VipRng::with_state(0x1203);
+        assert_eq!(rng.next_u8(&memory), 0x12 + 0x04);
+        assert_eq!(rng.state(), 0x1604);
+        assert_eq!(rng.next_u8(&memory), 0x16 + 0x05);
+    }
+
+    #[test]
+    fn zero_is_not_a_xorshift_state() {
+        assert_ne!(Rng::with_state(0).state(), 0);
+    }
+
+    #[test]
+    fn generators_survive_save_states() {
+        let memory = program();
+        for random in [Random::xorshift(Some(99)), Random::vip(Some(99))].iter() {
+            let mut random = *random;
+            random.next_u8(&memory);
+            let mut cpu = Cpu::new();
+            cpu.set_rng(random);
+            let (loaded, _) = savestate::load(&savestate::save(&cpu, &memory)).unwrap();
+            assert_eq!(loaded.rng(), random);
+            assert_eq!(numbers(loaded.rng(), &memory), numbers(random, &memory));
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::cpu::Cpu;
+use crate::memory::Memory;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Save states start with a magic number and a format version. The version is
+// bumped whenever the layout below changes; older files are rejected.
+const MAGIC: &[u8; 4] = b"C8SS";
+pub const VERSION: u16 = 3;
+
+// Serialize the complete machine state
+pub fn save(cpu: &Cpu, memory: &Memory) -> Vec<u8> {
+    let mut out = StateWriter::new();
+    cpu.save_state(&mut out);
+    memory.save_state(&mut out);
+    out.data
+}
+
+// Restore a machine state written by save
+pub fn load(data: &[u8]) -> io::Result<(Cpu, Memory)> {
+    let mut input = StateReader::new(data)?;
+    let cpu = Cpu::load_state(&mut input)?;
+    let memory = Memory::load_state(&mut input)?;
+    input.finish()?;
+    if memory.size() != Memory::for_quirks(cpu.quirks()).size() {
+        return Err(invalid("memory size does not match the quirks"));
+    }
+    Ok((cpu, memory))
+}
+
+pub fn save_to_file<P: AsRef<Path>>(path: P, cpu: &Cpu, memory: &Memory) -> io::Result<()> {
+    fs::write(path, save(cpu, memory))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<(Cpu, Memory)> {
+    load(&fs::read(path)?)
+}
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid save state: {}", message))
+}
+
+// Little-endian writer for the fields of a save state
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        Self { data }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(u8::from(value));
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Variable length data is prefixed with its length
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn new(data: &'a [u8]) -> io::Result<Self> {
+        let mut reader = Self { data };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a save state file"));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}, expected {}", version, VERSION)));
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < length {
+            return Err(invalid("unexpected end of file"));
+        }
+        let (head, tail) = self.data.split_at(length);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("trailing data"))
+        }
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bad boolean")),
+        }
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    // An XO-CHIP machine in hires that has drawn on both planes from a subroutine
+    fn machine() -> (Cpu, Memory) {
+        let quirks = Quirks::xo_chip();
+        let mut memory = Memory::for_quirks(quirks);
+        memory.load_font();
+        let program: Vec<u8> = [0x00FF, 0xF301, 0xA300, 0x6A0A, 0xDAA5, 0x2210, 0, 0, 0x1210_u16]
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect();
+        memory.load_rom(&program).unwrap();
+        for (offset, row) in [0xF0, 0x90, 0xF0, 0x90, 0xF0, 0xFF, 0x81, 0x81, 0x81, 0xFF].iter().enumerate() {
+            memory.set(0x300 + offset, *row).unwrap();
+        }
+        let mut cpu = Cpu::with_quirks(quirks);
+        for _ in 0..7 {
+            cpu.cycle(&mut memory).unwrap();
+        }
+        (cpu, memory)
+    }
+
+    fn error_kind(data: &[u8]) -> Option<io::ErrorKind> {
+        load(data).err().map(|error| error.kind())
+    }
+
+    #[test]
+    fn round_trip_keeps_the_whole_machine() {
+        let (cpu, memory) = machine();
+        let data = save(&cpu, &memory);
+        let (loaded_cpu, loaded_memory) = load(&data).unwrap();
+        assert_eq!(save(&loaded_cpu, &loaded_memory), data);
+        assert_eq!((loaded_cpu.width(), loaded_cpu.height()), (128, 64));
+        assert_eq!(loaded_cpu.framebuffer(), cpu.framebuffer());
+        assert!(loaded_cpu.framebuffer().contains(&0b11));
+        assert_eq!(loaded_cpu.pc(), 0x210);
+        assert_eq!(loaded_memory.size(), memory.size());
+        assert_eq!(loaded_memory.read8(0x309), Ok(0xFF));
+    }
+
+    #[test]
+    fn wrong_magic_or_version_is_rejected() {
+        let (cpu, memory) = machine();
+        let mut data = save(&cpu, &memory);
+        data[0] = b'X';
+        assert_eq!(error_kind(&data), Some(io::ErrorKind::InvalidData));
+        let mut data = save(&cpu, &memory);
+        data[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(error_kind(&data), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn truncated_or_padded_data_is_rejected() {
+        let (cpu, memory) = machine();
+        let data = save(&cpu, &memory);
+        for length in (0..data.len()).step_by(97).chain([data.len() - 1]) {
+            assert_eq!(error_kind(&data[..length]), Some(io::ErrorKind::InvalidData), "length {}", length);
+        }
+        let mut padded = data;
+        padded.push(0);
+        assert_eq!(error_kind(&padded), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::{Duration, Instant};
+
+// Length of one 60 Hz frame at normal speed
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+// Frames run at most in one go to catch up; any older backlog is dropped
+const MAX_CATCH_UP: u32 = 4;
+
+pub const MIN_SPEED: f64 = 0.125;
+pub const MAX_SPEED: f64 = 8.0;
+
+// Turns elapsed wall-clock time into a whole number of 60 Hz frames, so the
+// emulation speed does not depend on how often the host redraws
+pub struct Scheduler {
+    // Emulated frames per real frame, above 1 for turbo and below for slow motion
+    speed: f64,
+    // Scaled time not yet consumed by a frame
+    pending: Duration,
+    last: Instant,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::with_speed(1.0)
+    }
+
+    pub fn with_speed(speed: f64) -> Self {
+        Self {
+            speed: speed.clamp(MIN_SPEED, MAX_SPEED),
+            pending: Duration::ZERO,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    // Frames due since the previous call
+    pub fn frames(&mut self) -> u32 {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        self.advance(elapsed)
+    }
+
+    // Frames due after `elapsed` real time. A host that falls behind gets at
+    // most MAX_CATCH_UP frames and loses the rest, rather than a long burst.
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.pending += elapsed.mul_f64(self.speed);
+        let due = (self.pending.as_nanos() / FRAME.as_nanos()) as u32;
+        if due > MAX_CATCH_UP {
+            self.pending = Duration::ZERO;
+            MAX_CATCH_UP
+        } else {
+            self.pending -= FRAME * due;
+            due
+        }
+    }
+
+    // Forget elapsed time, e.g. after a pause, so it is not caught up on
+    pub fn reset(&mut self) {
+        self.pending = Duration::ZERO;
+        self.last = Instant::now();
+    }
+
+    // When the next frame becomes due
+    pub fn deadline(&self) -> Instant {
+        let remaining = FRAME.saturating_sub(self.pending);
+        self.last + remaining.div_f64(self.speed)
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frames run over one second of real time, redrawn every millisecond
+    fn frames_in_a_second(scheduler: &mut Scheduler) -> u32 {
+        (0..1000).map(|_| scheduler.advance(Duration::from_millis(1))).sum()
+    }
+
+    #[test]
+    fn normal_speed_runs_sixty_frames_a_second() {
+        assert_eq!(frames_in_a_second(&mut Scheduler::new()), 60);
+    }
+
+    #[test]
+    fn half_speed_runs_thirty_frames_a_second() {
+        assert_eq!(frames_in_a_second(&mut Scheduler::with_speed(0.5)), 30);
+    }
+
+    #[test]
+    fn a_stall_is_not_caught_up_on() {
+        let mut scheduler = Scheduler::new();
+        assert_eq!(scheduler.advance(Duration::from_secs(2)), MAX_CATCH_UP);
+        // The backlog is gone, so the next millisecond has nothing due
+        assert_eq!(scheduler.advance(Duration::from_millis(1)), 0);
+        assert_eq!(scheduler.advance(FRAME * 3), 3);
+    }
+
+    #[test]
+    fn speed_is_clamped() {
+        let mut scheduler = Scheduler::with_speed(100.0);
+        assert_eq!(scheduler.speed(), MAX_SPEED);
+        scheduler.set_speed(0.0);
+        assert_eq!(scheduler.speed(), MIN_SPEED);
+        scheduler.set_speed(2.0);
+        assert_eq!(scheduler.speed(), 2.0);
+    }
+}
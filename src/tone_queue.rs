@@ -0,0 +1,182 @@
+use crate::audio::Tone;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+// Changes in flight at once. The audio callback drains the queue every few
+// milliseconds, so it only fills up when the callback stalls.
+const CAPACITY: usize = 256;
+
+// A change of the beeper, taking effect at the start of an emulated frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ToneChange {
+    pub frame: u64,
+    pub tone: Tone,
+}
+
+// Hands beeper changes from the emulator to the audio callback without
+// locking, so the callback never waits on the emulator. There is exactly one
+// producer and one consumer: a slot is filled before `tail` publishes it, and
+// read before `head` hands it back.
+pub struct ToneQueue {
+    slots: Vec<Slot>,
+    // Next slot to read, only advanced by the consumer
+    head: AtomicUsize,
+    // Next slot to write, only advanced by the producer
+    tail: AtomicUsize,
+    // Emulated frames reported so far
+    frames: AtomicU64,
+}
+
+// A ToneChange spread over atomics: the frame, then on/pattern flags with the
+// pitch in bits 8-15, then the 128 bit pattern
+#[derive(Default)]
+struct Slot {
+    frame: AtomicU64,
+    flags: AtomicU32,
+    pattern: [AtomicU64; 2],
+}
+
+impl ToneQueue {
+    pub fn new() -> Self {
+        Self {
+            slots: (0..CAPACITY).map(|_| Slot::default()).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            frames: AtomicU64::new(0),
+        }
+    }
+
+    // Called by the producer. False when the queue is full and the change was dropped.
+    pub fn push(&self, change: ToneChange) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == CAPACITY {
+            return false;
+        }
+        let slot = &self.slots[tail % CAPACITY];
+        let tone = change.tone;
+        let pattern = tone.pattern.unwrap_or([0; 16]);
+        let mut flags = u32::from(tone.on) | (u32::from(tone.pitch) << 8);
+        if tone.pattern.is_some() {
+            flags |= 2;
+        }
+        slot.frame.store(change.frame, Ordering::Relaxed);
+        slot.flags.store(flags, Ordering::Relaxed);
+        for (half, bytes) in slot.pattern.iter().zip(pattern.chunks(8)) {
+            let mut word = [0; 8];
+            word.copy_from_slice(bytes);
+            half.store(u64::from_be_bytes(word), Ordering::Relaxed);
+        }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // Called by the consumer. The oldest change, which stays queued until popped.
+    pub fn peek(&self) -> Option<ToneChange> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = &self.slots[head % CAPACITY];
+        let flags = slot.flags.load(Ordering::Relaxed);
+        let mut pattern = [0; 16];
+        for (bytes, half) in pattern.chunks_mut(8).zip(slot.pattern.iter()) {
+            bytes.copy_from_slice(&half.load(Ordering::Relaxed).to_be_bytes());
+        }
+        Some(ToneChange {
+            frame: slot.frame.load(Ordering::Relaxed),
+            tone: Tone {
+                on: flags & 1 != 0,
+                pattern: if flags & 2 != 0 { Some(pattern) } else { None },
+                pitch: (flags >> 8) as u8,
+            },
+        })
+    }
+
+    // Called by the consumer to drop the change returned by peek
+    pub fn pop(&self) {
+        let head = self.head.load(Ordering::Relaxed);
+        if head != self.tail.load(Ordering::Acquire) {
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Acquire)
+    }
+
+    pub fn set_frames(&self, frames: u64) {
+        self.frames.store(frames, Ordering::Release);
+    }
+}
+
+impl Default for ToneQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(frame: u64) -> ToneChange {
+        ToneChange {
+            frame,
+            tone: Tone {
+                on: frame & 1 == 0,
+                pattern: if frame & 2 != 0 { Some([frame as u8; 16]) } else { None },
+                pitch: frame as u8,
+            },
+        }
+    }
+
+    #[test]
+    fn changes_come_out_in_order() {
+        let queue = ToneQueue::new();
+        assert_eq!(queue.peek(), None);
+        for frame in 1..=3 {
+            assert!(queue.push(change(frame)));
+        }
+        for frame in 1..=3 {
+            // Peeking leaves the change queued
+            assert_eq!(queue.peek(), Some(change(frame)));
+            assert_eq!(queue.peek(), Some(change(frame)));
+            queue.pop();
+        }
+        assert_eq!(queue.peek(), None);
+        // Popping an empty queue does nothing
+        queue.pop();
+        assert!(queue.push(change(4)));
+        assert_eq!(queue.peek(), Some(change(4)));
+    }
+
+    #[test]
+    fn push_fails_when_full() {
+        let queue = ToneQueue::new();
+        for frame in 0..CAPACITY as u64 {
+            assert!(queue.push(change(frame)));
+        }
+        assert!(!queue.push(change(CAPACITY as u64)));
+        queue.pop();
+        assert!(queue.push(change(CAPACITY as u64)));
+        assert_eq!(queue.peek(), Some(change(1)));
+    }
+
+    #[test]
+    fn slots_are_reused_after_wrapping_around() {
+        let queue = ToneQueue::new();
+        for frame in 0..CAPACITY as u64 * 3 + 10 {
+            assert!(queue.push(change(frame)));
+            assert_eq!(queue.peek(), Some(change(frame)));
+            queue.pop();
+        }
+        assert_eq!(queue.peek(), None);
+    }
+
+    #[test]
+    fn frames_are_shared() {
+        let queue = ToneQueue::new();
+        assert_eq!(queue.frames(), 0);
+        queue.set_frames(42);
+        assert_eq!(queue.frames(), 42);
+    }
+}
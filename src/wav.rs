@@ -0,0 +1,73 @@
+use crate::audio::{AudioSettings, AudioSink, Beeper, Tone};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const SAMPLE_RATE: u32 = 44100;
+// 44100 divides evenly into 60 Hz frames, so every frame is the same length
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+
+// Renders the beeper into a 16-bit mono WAV file, written when the run finishes
+pub struct WavSink {
+    path: PathBuf,
+    beeper: Beeper,
+    samples: Vec<i16>,
+}
+
+impl WavSink {
+    pub fn new<P: Into<PathBuf>>(path: P, settings: AudioSettings) -> Self {
+        Self {
+            path: path.into(),
+            beeper: Beeper::new(settings, SAMPLE_RATE as f32),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    fn push(&mut self, tone: &Tone) {
+        let value = self.beeper.next_sample(tone);
+        self.samples.push((value * i16::MAX as f32) as i16);
+    }
+}
+
+impl AudioSink for WavSink {
+    fn frame(&mut self, tone: &Tone) {
+        for _ in 0..SAMPLES_PER_FRAME {
+            self.push(tone);
+        }
+    }
+
+    // Let the last tone fade out rather than end the file mid-wave
+    fn finish(&mut self) -> io::Result<()> {
+        while !self.beeper.is_silent() {
+            self.push(&Tone::default());
+        }
+        fs::write(&self.path, encode(&self.samples, SAMPLE_RATE))
+    }
+}
+
+// A canonical 44 byte header followed by the samples as little endian PCM
+pub fn encode(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+    let data_size = (samples.len() * 2) as u32;
+    let mut out = Vec::with_capacity(44 + samples.len() * 2);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_size).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    out.extend_from_slice(&1u16.to_le_bytes()); // mono
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // bytes per second
+    out.extend_from_slice(&2u16.to_le_bytes()); // bytes per sample
+    out.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    out
+}
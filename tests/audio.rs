@@ -0,0 +1,68 @@
+// Checks the sound output of headless runs, without an audio device.
+// roms/audio.ch8 sets the sound timer to 60 and then jumps to itself.
+
+use chip8::audio::{AudioSettings, AudioSink, Tone};
+use chip8::cpu::Cpu;
+use chip8::headless::{self, Stop};
+use chip8::memory::Memory;
+use chip8::quirks::Quirks;
+use chip8::wav::{self, WavSink, SAMPLE_RATE};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+// Remembers whether the beeper was on in each frame
+struct Frames(Vec<bool>);
+
+impl AudioSink for Frames {
+    fn frame(&mut self, tone: &Tone) {
+        self.0.push(tone.on);
+    }
+}
+
+fn run(rom: &str, audio: &mut dyn AudioSink) -> Stop {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.extend(&["roms", rom]);
+    let data = fs::read(path).expect("ROM is readable");
+    let mut memory = Memory::new();
+    memory.load_font();
+    memory.load_rom(&data).expect("ROM fits in memory");
+    let mut cpu = Cpu::with_quirks(Quirks::cosmac_vip());
+    headless::run(&mut cpu, &mut memory, 10_000, INSTRUCTIONS_PER_FRAME, &mut || [false; 16], audio)
+        .unwrap_or_else(|e| panic!("{} faulted: {}", rom, e))
+}
+
+#[test]
+fn sound_timer_beeps_for_its_value_in_frames() {
+    let mut frames = Frames(Vec::new());
+    let stop = run("audio.ch8", &mut frames);
+    assert!(matches!(stop, Stop::InfiniteLoop { .. }));
+
+    let first = frames.0.iter().position(|on| *on).expect("the ROM beeps");
+    assert_eq!(frames.0[first..].len(), 60);
+    assert!(frames.0[first..].iter().all(|on| *on));
+}
+
+#[test]
+fn wav_holds_one_second_of_tone() {
+    let path = env::temp_dir().join(format!("chip8-audio-test-{}.wav", std::process::id()));
+    let mut sink = WavSink::new(&path, AudioSettings::default());
+    run("audio.ch8", &mut sink);
+    sink.finish().expect("WAV is writable");
+    let bytes = fs::read(&path).expect("WAV is readable");
+    fs::remove_file(&path).ok();
+
+    let samples = sink.samples();
+    assert_eq!(bytes, wav::encode(samples, SAMPLE_RATE));
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(&bytes[8..12], b"WAVE");
+
+    let first = samples.iter().position(|s| *s != 0).expect("the WAV is not silent");
+    let last = samples.iter().rposition(|s| *s != 0).unwrap();
+    let seconds = (last - first + 1) as f32 / SAMPLE_RATE as f32;
+    // One second of tone plus the release of the envelope
+    let release = AudioSettings::default().release;
+    assert!((seconds - 1.0 - release).abs() < 0.001, "tone lasted {} s", seconds);
+}
@@ -0,0 +1,133 @@
+// Runs the bundled ROMs headlessly and compares the final framebuffer with the
+// ASCII art in tests/golden. Set UPDATE_GOLDEN=1 to rewrite the images after an
+// intended change in behaviour.
+// Every run uses the same seed, so ROMs that draw random numbers are reproducible.
+
+use chip8::audio::NullSink;
+use chip8::cpu::Cpu;
+use chip8::dump;
+use chip8::headless::{self, Stop};
+use chip8::memory::Memory;
+use chip8::quirks::Quirks;
+use chip8::rng::Random;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+const SEED: u32 = 1;
+
+fn path(parts: &[&str]) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.extend(parts);
+    path
+}
+
+fn run(rom: &str, quirks: Quirks, cycles: u64) -> (Cpu, Stop) {
+    let data = fs::read(path(&["roms", rom])).expect("ROM is readable");
+    let mut memory = Memory::new();
+    memory.load_font();
+    memory.load_rom(&data).expect("ROM fits in memory");
+    let mut cpu = Cpu::with_quirks(quirks);
+    cpu.set_rng(Random::xorshift(Some(SEED)));
+    let mut keys = || [false; 16];
+    let stop = headless::run(&mut cpu, &mut memory, cycles, INSTRUCTIONS_PER_FRAME, &mut keys, &mut NullSink)
+        .unwrap_or_else(|e| panic!("{} faulted: {}", rom, e));
+    (cpu, stop)
+}
+
+// Mark pixels that are only lit in the expected image with '-' and pixels that
+// are only lit in the actual image with '+'
+fn pixel_diff(expected: &str, actual: &str) -> (usize, String) {
+    let mut count = 0;
+    let mut out = String::new();
+    for (expected_row, actual_row) in expected.lines().zip(actual.lines()) {
+        for (e, a) in expected_row.chars().zip(actual_row.chars()) {
+            if e == a {
+                out.push(a);
+            } else {
+                count += 1;
+                out.push(if a == '.' { '-' } else { '+' });
+            }
+        }
+        out.push('\n');
+    }
+    (count, out)
+}
+
+fn check(name: &str, cpu: &Cpu) {
+    let actual = dump::ascii(cpu);
+    let golden = path(&["tests", "golden", &format!("{}.txt", name)]);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&golden, &actual).expect("golden image is writable");
+        return;
+    }
+    let expected = fs::read_to_string(&golden)
+        .unwrap_or_else(|_| panic!("missing {}, run with UPDATE_GOLDEN=1 to create it", golden.display()));
+    if expected != actual {
+        let (count, diff) = pixel_diff(&expected, &actual);
+        if expected.lines().count() != actual.lines().count() || count == 0 {
+            panic!("{}: resolution changed, got\n{}", name, actual);
+        }
+        panic!("{}: {} pixels differ from {}\n{}", name, count, golden.display(), diff);
+    }
+}
+
+#[test]
+fn ibm_logo() {
+    let (cpu, stop) = run("ibm.ch8", Quirks::cosmac_vip(), 1000);
+    assert_eq!(stop, Stop::InfiniteLoop { address: 0x228 });
+    check("ibm", &cpu);
+}
+
+#[test]
+fn opcode_test() {
+    let (cpu, stop) = run("test_opcode.ch8", Quirks::cosmac_vip(), 10_000);
+    assert!(matches!(stop, Stop::InfiniteLoop { .. }));
+    check("test_opcode", &cpu);
+}
+
+#[test]
+fn bc_test() {
+    let (cpu, stop) = run("BC_test.ch8", Quirks::chip48(), 10_000);
+    assert!(matches!(stop, Stop::InfiniteLoop { .. }));
+    check("BC_test", &cpu);
+}
+
+#[test]
+fn audio() {
+    let (cpu, stop) = run("audio.ch8", Quirks::cosmac_vip(), 10_000);
+    assert!(matches!(stop, Stop::InfiniteLoop { .. }));
+    check("audio", &cpu);
+}
+
+#[test]
+fn airplane_title() {
+    let (cpu, _) = run("Airplane.ch8", Quirks::cosmac_vip(), 3000);
+    check("Airplane", &cpu);
+}
+
+#[test]
+fn maze() {
+    let (cpu, stop) = run("maze.ch8", Quirks::cosmac_vip(), 10_000);
+    assert!(matches!(stop, Stop::InfiniteLoop { .. }));
+    check("maze", &cpu);
+}
+
+#[test]
+fn particle() {
+    let (cpu, _) = run("particle.ch8", Quirks::cosmac_vip(), 5000);
+    check("particle", &cpu);
+}
+
+#[test]
+fn trip8() {
+    let (cpu, _) = run("trip8.ch8", Quirks::cosmac_vip(), 20_000);
+    check("trip8", &cpu);
+}
+
+#[test]
+fn coinflip() {
+    let (cpu, _) = run("coinflip.ch8", Quirks::cosmac_vip(), 5000);
+    check("coinflip", &cpu);
+}